@@ -0,0 +1,19 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use transactions_engine::transaction::Transaction;
+use transactions_engine::Service;
+
+// Any sequence of transactions may be rejected, but must neither panic
+// nor leave an account with funds that don't add up
+fuzz_target!(|txs: Vec<Transaction>| {
+    let mut service = Service::new();
+    for tx in &txs {
+        let _ = service.apply(tx);
+    }
+    for account in service.accounts() {
+        account
+            .check_invariants()
+            .expect("Account violates invariants");
+    }
+});
@@ -1,13 +1,17 @@
-use std::collections::HashMap;
+use alloc::{collections::VecDeque, vec::Vec};
 use thiserror::Error;
 
-use crate::transaction::{Amount, ClientId, Transaction, TransactionId, TransactionType};
+#[cfg(feature = "std")]
+use crate::store::StoreError;
+use crate::transaction::{
+    Amount, ClientId, RoundingMode, Transaction, TransactionId, TransactionType,
+};
 
 /// Possible errors that can happen when applying a transaction
 #[derive(Error, Debug)]
 pub enum TransactionError {
-    #[error("Unsufficient funds to handle transaction `{0:?}`")]
-    UnsufficientFunds(Transaction),
+    #[error("Unsufficient funds to handle transaction `{0}`")]
+    UnsufficientFunds(TransactionId),
     #[error("Account is locked. Can't perform transaction.")]
     AccountLocked,
     #[error("Transaction `{0}` is already under dispute")]
@@ -18,28 +22,363 @@ pub enum TransactionError {
     NotFound(TransactionId),
     #[error("Transaction with ID `{0}` already exist")]
     AlreadyExist(TransactionId),
+    #[error("Transaction `{0}` was already charged back")]
+    AlreadyChargedBack(TransactionId),
+    #[error("Transaction `{0}` belongs to a different client")]
+    ClientMismatch(TransactionId),
+    #[error("Account `{0}` violates accounting invariants")]
+    InvariantViolated(ClientId),
+    #[error("Withdrawal `{0}` overdrew the account and can't be disputed")]
+    OverdrawnWithdrawal(TransactionId),
+    #[error("Withdrawal `{0}` exceeded allowed withdrawal velocity, account got locked")]
+    VelocityExceeded(TransactionId),
+    #[error("Funds of withdrawal `{0}` are already back on the account, can't dispute it")]
+    FundsRestored(TransactionId),
+    #[error("Transaction `{0}` is a replay of an already applied one")]
+    OutOfOrder(TransactionId),
+    #[error("History entry of transaction `{0}` can't be disputed, history is corrupted")]
+    CorruptHistory(TransactionId),
+    #[error("Client `{0}` has no account, only a deposit can open one")]
+    UnknownClient(ClientId),
+    #[error("Deposit `{0}` would bring total funds above the limit of the account")]
+    LimitExceeded(TransactionId),
+    #[error("Charged back funds of `{0}` can't go to the destination, it is locked or the client")]
+    DestinationUnavailable(TransactionId),
+    #[cfg(feature = "std")]
+    #[error("Failed to persist account: {0}")]
+    Storage(#[from] StoreError),
 }
 
+impl TransactionError {
+    /// Get name of the error variant, usable as a key for grouping errors
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionError::UnsufficientFunds(_) => "UnsufficientFunds",
+            TransactionError::AccountLocked => "AccountLocked",
+            TransactionError::AlreadyDisputed(_) => "AlreadyDisputed",
+            TransactionError::NotDisputed(_) => "NotDisputed",
+            TransactionError::NotFound(_) => "NotFound",
+            TransactionError::AlreadyExist(_) => "AlreadyExist",
+            TransactionError::AlreadyChargedBack(_) => "AlreadyChargedBack",
+            TransactionError::ClientMismatch(_) => "ClientMismatch",
+            TransactionError::InvariantViolated(_) => "InvariantViolated",
+            TransactionError::OverdrawnWithdrawal(_) => "OverdrawnWithdrawal",
+            TransactionError::VelocityExceeded(_) => "VelocityExceeded",
+            TransactionError::FundsRestored(_) => "FundsRestored",
+            TransactionError::OutOfOrder(_) => "OutOfOrder",
+            TransactionError::CorruptHistory(_) => "CorruptHistory",
+            TransactionError::UnknownClient(_) => "UnknownClient",
+            TransactionError::LimitExceeded(_) => "LimitExceeded",
+            TransactionError::DestinationUnavailable(_) => "DestinationUnavailable",
+            #[cfg(feature = "std")]
+            TransactionError::Storage(_) => "Storage",
+        }
+    }
+
+    /// Get stable machine readable code of the error, meant for integrations
+    ///
+    /// Unlike messages and variant names, codes don't change between versions
+    pub fn code(&self) -> &'static str {
+        match self {
+            TransactionError::UnsufficientFunds(_) => "E_INSUFFICIENT_FUNDS",
+            TransactionError::AccountLocked => "E_ACCOUNT_LOCKED",
+            TransactionError::AlreadyDisputed(_) => "E_ALREADY_DISPUTED",
+            TransactionError::NotDisputed(_) => "E_NOT_DISPUTED",
+            TransactionError::NotFound(_) => "E_NOT_FOUND",
+            TransactionError::AlreadyExist(_) => "E_ALREADY_EXISTS",
+            TransactionError::AlreadyChargedBack(_) => "E_ALREADY_CHARGED_BACK",
+            TransactionError::ClientMismatch(_) => "E_CLIENT_MISMATCH",
+            TransactionError::InvariantViolated(_) => "E_INVARIANT_VIOLATED",
+            TransactionError::OverdrawnWithdrawal(_) => "E_OVERDRAWN_WITHDRAWAL",
+            TransactionError::VelocityExceeded(_) => "E_VELOCITY_EXCEEDED",
+            TransactionError::FundsRestored(_) => "E_FUNDS_RESTORED",
+            TransactionError::OutOfOrder(_) => "E_OUT_OF_ORDER",
+            TransactionError::CorruptHistory(_) => "E_CORRUPT_HISTORY",
+            TransactionError::UnknownClient(_) => "E_UNKNOWN_CLIENT",
+            TransactionError::LimitExceeded(_) => "E_LIMIT_EXCEEDED",
+            TransactionError::DestinationUnavailable(_) => "E_DESTINATION_UNAVAILABLE",
+            #[cfg(feature = "std")]
+            TransactionError::Storage(_) => "E_STORAGE",
+        }
+    }
+}
+
+/// Map of transactions by their ids, there is no `HashMap` without `std`
+#[cfg(feature = "std")]
+type TxMap<V> = std::collections::HashMap<TransactionId, V>;
+#[cfg(not(feature = "std"))]
+type TxMap<V> = alloc::collections::BTreeMap<TransactionId, V>;
+
+/// Tolerance for floating point errors, well below the precision of amounts
+const EPSILON: f64 = 1e-9;
+
 /// Result type used when operating on account
 pub type TransactionResult<T> = Result<T, TransactionError>;
 
+/// Outcome of a transaction that wasn't rejected
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TransactionOutcome {
+    /// Transaction took effect on the account
+    Applied,
+    /// Transaction took effect and locked the account, as charging back does
+    AppliedLockedAccount,
+    /// Transaction was skipped without changing anything, eg. a replay skipped by the service
+    Ignored,
+}
+
 /// Wrapper for transaction that remembers if there is an open dispute
-#[derive(Debug)]
+///
+/// Amount of the transaction is the one it moved, also when a withdrawal got partly filled.
+/// It is serialized exactly as it was applied, see `StoredTransaction`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(from = "StoredTransaction", into = "StoredTransaction")]
 pub struct DisputableTransaction {
     transaction: Transaction,
     disputed: bool,
+    /// Set once a dispute of the transaction ended with a chargeback
+    charged_back: bool,
+    /// Set for withdrawals that drove available funds below zero
+    overdrew: bool,
+    /// Value of `clock` of the account when the transaction was applied
+    made_at: u64,
+}
+
+/// Serialized form of `DisputableTransaction`
+///
+/// `Amount` is serialized rounded to the current precision, so the amount of the transaction
+/// is stored next to it exactly, and restored from there, so that disputes hold and release
+/// the same quantum even if precision changed since the transaction was applied.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StoredTransaction {
+    transaction: Transaction,
+    #[serde(with = "exact_amount")]
+    amount: Amount,
+    disputed: bool,
+    charged_back: bool,
+    overdrew: bool,
+    #[serde(default)]
+    made_at: u64,
+}
+
+impl From<StoredTransaction> for DisputableTransaction {
+    fn from(stored: StoredTransaction) -> Self {
+        Self {
+            transaction: Transaction {
+                amount: stored.amount,
+                ..stored.transaction
+            },
+            disputed: stored.disputed,
+            charged_back: stored.charged_back,
+            overdrew: stored.overdrew,
+            made_at: stored.made_at,
+        }
+    }
+}
+
+impl From<DisputableTransaction> for StoredTransaction {
+    fn from(entry: DisputableTransaction) -> Self {
+        Self {
+            amount: entry.transaction.amount,
+            transaction: entry.transaction,
+            disputed: entry.disputed,
+            charged_back: entry.charged_back,
+            overdrew: entry.overdrew,
+            made_at: entry.made_at,
+        }
+    }
+}
+
+/// Serialization of amounts stored in history, which unlike `Amount` isn't rounded
+/// to the current precision
+mod exact_amount {
+    use crate::transaction::Amount;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(amount: &Amount, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(amount.0)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Amount, D::Error> {
+        f64::deserialize(deserializer).map(Amount)
+    }
+}
+
+/// Reasons for an account to get locked
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum LockReason {
+    /// Dispute of a transaction ended with a chargeback
+    Chargeback,
+    /// Withdrawals were made faster than allowed by `VelocityRule`
+    Velocity,
+}
+
+/// Limit of withdrawals an account can make within a window before getting locked
+///
+/// Transactions carry no timestamps, so the window is measured in the number of
+/// transactions processed by the account. Withdrawal that would exceed the limit
+/// is rejected and the account gets locked.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct VelocityRule {
+    /// Maximum number of withdrawals within the window, including rejected ones
+    pub max_withdrawals: usize,
+    /// Number of most recent transactions of the account the limit applies to
+    pub window: u64,
+}
+
+/// Transactions a locked account still accepts
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum LockedAccountPolicy {
+    /// No transaction takes effect on a locked account
+    #[default]
+    BlockAll,
+    /// Deposits are still accepted, eg. to allow restitution, everything else is blocked
+    AllowDeposits,
+}
+
+impl LockedAccountPolicy {
+    /// Check if a locked account accepts transactions of given type
+    pub fn permits(self, r#type: TransactionType) -> bool {
+        match self {
+            LockedAccountPolicy::BlockAll => false,
+            LockedAccountPolicy::AllowDeposits => r#type == TransactionType::Deposit,
+        }
+    }
+}
+
+/// Handling of a deposit or withdrawal reusing an id of one in history
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Reject the transaction with `TransactionError::AlreadyExist`
+    #[default]
+    Reject,
+    /// Treat the transaction as a newer version of the one in history, if it is of the same
+    /// type and was neither disputed nor charged back, otherwise reject it
+    ///
+    /// Replacing undoes the effect of the older transaction on the balances and applies
+    /// the newer one in its place, following the same rules as any other transaction.
+    /// Replacement is rejected as a whole, leaving the older transaction in place, if
+    /// the newer one is rejected or if replacing a deposit would leave available funds
+    /// below zero, eg. when funds of the older one were withdrawn already.
+    ReplaceIfNotDisputed,
+}
+
+/// Policies deciding how an account handles cases not covered by the specification
+#[derive(Debug, Copy, Clone)]
+pub struct AccountPolicy {
+    /// Allow withdrawals to drive available funds below zero
+    pub allow_overdraft: bool,
+    /// Allow disputing withdrawals that drove available funds below zero
+    pub dispute_overdrawn_withdrawals: bool,
+    /// Allow disputing deposits whose funds were already partly withdrawn
+    ///
+    /// By default such dispute is rejected, as holding the deposited amount would require
+    /// available funds to go below zero. When allowed, the whole deposited amount is held
+    /// anyway and available funds become negative.
+    pub allow_negative_available: bool,
+    /// Lock accounts making withdrawals too fast, disabled by default
+    pub velocity_lock: Option<VelocityRule>,
+    /// Reject disputes of withdrawals whose funds are already back on the account
+    ///
+    /// Funds are considered back when holding the withdrawn amount would bring total funds
+    /// above the sum of deposits that weren't charged back. Funds credited to an account
+    /// outside of deposits, eg. as a chargeback destination, count as such funds.
+    pub check_withdrawal_disputes: bool,
+    /// Transactions accepted by an account once it got locked
+    pub locked_account: LockedAccountPolicy,
+    /// Fill withdrawals exceeding available funds partially, withdrawing all that is available
+    ///
+    /// The withdrawal is stored with the filled amount, so that disputing it holds what
+    /// was actually withdrawn. Overdraft, when allowed, takes precedence.
+    pub partial_withdrawal: bool,
+    /// Rounding of deposited and withdrawn amounts to the current precision
+    pub rounding: DirectionalRounding,
+    /// Ceiling of total funds, deposits that would exceed it are rejected, no limit by default
+    pub max_total: Option<Amount>,
+    /// Handling of deposits and withdrawals reusing an id, rejected by default
+    pub on_duplicate: DuplicatePolicy,
+}
+
+/// Rounding of amounts of deposits and withdrawals, set separately for each direction
+///
+/// Amounts read from input are truncated to the current precision already, so this matters
+/// for transactions built by the caller. By default amounts are applied as given.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct DirectionalRounding {
+    pub deposits: Option<RoundingMode>,
+    pub withdrawals: Option<RoundingMode>,
+}
+
+impl DirectionalRounding {
+    /// Get the rounding mode of given transaction type, if its amounts are rounded
+    fn mode(self, r#type: TransactionType) -> Option<RoundingMode> {
+        match r#type {
+            TransactionType::Deposit => self.deposits,
+            TransactionType::Withdrawal => self.withdrawals,
+            _ => None,
+        }
+    }
+}
+
+impl Default for AccountPolicy {
+    fn default() -> Self {
+        Self {
+            allow_overdraft: false,
+            dispute_overdrawn_withdrawals: true,
+            allow_negative_available: false,
+            velocity_lock: None,
+            check_withdrawal_disputes: false,
+            locked_account: LockedAccountPolicy::default(),
+            partial_withdrawal: false,
+            rounding: DirectionalRounding::default(),
+            max_total: None,
+            on_duplicate: DuplicatePolicy::default(),
+        }
+    }
+}
+
+/// Changes of an account made by applying a transaction
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BalanceDelta {
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    /// Set if the transaction locked or unlocked the account
+    pub lock_changed: bool,
 }
 
 /// Model of user account
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+///
+/// Serialized account includes its history, so that it can be restored from a snapshot
+/// and still handle disputes of earlier transactions. Policy is not a part of the state
+/// and is given by the service restoring the account.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Account {
     id: ClientId,
     available: Amount,
     held: Amount,
     total: Amount,
     locked: bool,
+    #[serde(default)]
+    lock_reason: Option<LockReason>,
+    #[serde(default)]
+    tx_history: TxMap<DisputableTransaction>,
     #[serde(skip)]
-    tx_history: HashMap<TransactionId, DisputableTransaction>,
+    policy: AccountPolicy,
+    /// Number of transactions processed by the account, used to measure velocity
+    #[serde(default)]
+    clock: u64,
+    /// Values of `clock` at which recent withdrawals were made
+    #[serde(default)]
+    recent_withdrawals: VecDeque<u64>,
+    /// Number of transactions applied successfully
+    #[serde(default)]
+    transaction_count: u64,
+    /// Sum of amounts of applied deposits, including ones charged back since
+    #[serde(default)]
+    deposit_volume: Amount,
+    /// Sum of amounts of applied withdrawals, including ones charged back since
+    #[serde(default)]
+    withdrawal_volume: Amount,
 }
 
 impl Default for Account {
@@ -50,7 +389,14 @@ impl Default for Account {
             total: Amount(0.),
             held: Amount(0.),
             locked: false,
-            tx_history: HashMap::new(),
+            lock_reason: None,
+            tx_history: TxMap::new(),
+            policy: AccountPolicy::default(),
+            clock: 0,
+            recent_withdrawals: VecDeque::new(),
+            transaction_count: 0,
+            deposit_volume: Amount(0.),
+            withdrawal_volume: Amount(0.),
         }
     }
 }
@@ -64,57 +410,524 @@ impl Account {
         }
     }
 
+    /// Create a new account assigned to `client_id` that follows given `policy`
+    pub fn with_policy(client_id: ClientId, policy: AccountPolicy) -> Self {
+        Self {
+            id: client_id,
+            policy,
+            ..Default::default()
+        }
+    }
+
+    /// Create an account with given balances and no history, meant for tests and fuzzing
+    ///
+    /// Total funds are the sum of `available` and `held`, so that the invariants hold.
+    #[cfg(feature = "fuzzing")]
+    pub fn with_balances(
+        client_id: ClientId,
+        available: Amount,
+        held: Amount,
+        lock_reason: Option<LockReason>,
+    ) -> Self {
+        Self {
+            id: client_id,
+            available,
+            held,
+            total: Amount(available.0 + held.0),
+            locked: lock_reason.is_some(),
+            lock_reason,
+            ..Default::default()
+        }
+    }
+
     /// Get id assigned to given account
     pub fn id(&self) -> ClientId {
         self.id
     }
 
+    /// Get funds available for withdrawal
+    pub fn available(&self) -> Amount {
+        self.available
+    }
+
+    /// Get funds held by open disputes
+    pub fn held(&self) -> Amount {
+        self.held
+    }
+
+    /// Get total funds, both available and held
+    pub fn total(&self) -> Amount {
+        self.total
+    }
+
+    /// Get the number of transactions applied successfully on the account
+    pub fn transaction_count(&self) -> u64 {
+        self.transaction_count
+    }
+
+    /// Get the gross amount deposited on the account, regardless of later chargebacks
+    pub fn deposit_volume(&self) -> Amount {
+        self.deposit_volume
+    }
+
+    /// Get the gross amount withdrawn from the account, regardless of later chargebacks
+    pub fn withdrawal_volume(&self) -> Amount {
+        self.withdrawal_volume
+    }
+
+    /// Check if account got locked
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Get the reason of locking the account, `None` if it is not locked
+    pub fn lock_reason(&self) -> Option<LockReason> {
+        self.lock_reason
+    }
+
+    /// Lock the account, no further transactions will take effect
+    fn lock(&mut self, reason: LockReason) {
+        self.locked = true;
+        self.lock_reason = Some(reason);
+    }
+
+    /// Unlock the account, allowing further transactions to take effect
+    ///
+    /// This is an administrative action, meant to be taken after investigating the reason
+    /// of the lock, and never happens as a part of processing transactions. Charged back
+    /// transactions stay charged back and recent withdrawals are forgotten, so that the
+    /// velocity limit doesn't lock the account again right away.
+    pub fn unlock(&mut self) {
+        self.locked = false;
+        self.lock_reason = None;
+        self.recent_withdrawals.clear();
+    }
+
+    /// Void deposit `tx` made by mistake, returning its amount
+    ///
+    /// This is an administrative action like `unlock`. The deposit must not be disputed
+    /// nor charged back and its whole amount must still be available. It is subtracted
+    /// from the funds and the deposit is removed from tx_history, freeing its id. Its activity
+    /// is undone too, as if the deposit never happened.
+    pub fn void_deposit(&mut self, tx: TransactionId) -> TransactionResult<Amount> {
+        let deposit = match self.tx_history.get(&tx) {
+            Some(deposit) if deposit.transaction.r#type == TransactionType::Deposit => deposit,
+            _ => return Err(TransactionError::NotFound(tx)),
+        };
+        if deposit.charged_back {
+            return Err(TransactionError::AlreadyChargedBack(tx));
+        }
+        if deposit.disputed {
+            return Err(TransactionError::AlreadyDisputed(tx));
+        }
+        let amount = deposit.transaction.amount;
+        if self.available < amount {
+            return Err(TransactionError::UnsufficientFunds(tx));
+        }
+        self.available -= amount;
+        self.total -= amount;
+        self.deposit_volume -= amount;
+        // Snapshots older than the counters restore them at zero
+        self.transaction_count = self.transaction_count.saturating_sub(1);
+        self.tx_history.remove(&tx);
+        Ok(amount)
+    }
+
+    /// Check if withdrawal at current `clock` would exceed velocity limit of the policy
+    fn exceeds_velocity(&mut self) -> bool {
+        let Some(rule) = self.policy.velocity_lock else {
+            return false;
+        };
+        while let Some(&made_at) = self.recent_withdrawals.front() {
+            if made_at + rule.window > self.clock {
+                break;
+            }
+            self.recent_withdrawals.pop_front();
+        }
+        self.recent_withdrawals.len() >= rule.max_withdrawals
+    }
+
+    /// Check if available funds cover a withdrawal of `amount` in full
+    ///
+    /// Amounts are compared after rounding to current precision, so that float errors
+    /// of summing up deposits don't turn down an exactly affordable withdrawal
+    fn covers(&self, amount: Amount) -> bool {
+        self.available >= amount
+    }
+
+    /// Check if a withdrawal of `amount` would be applied in full, without applying it
+    ///
+    /// Follows the same rules as `apply`, including rounding of withdrawals, locks, velocity
+    /// limits and overdrafts allowed by the policy. Withdrawal only filled partially
+    /// by `AccountPolicy::partial_withdrawal` doesn't count. Reuse of the transaction id
+    /// isn't checked, as the withdrawal has none yet.
+    pub fn can_withdraw(&self, amount: Amount) -> bool {
+        if self.locked
+            && !self
+                .policy
+                .locked_account
+                .permits(TransactionType::Withdrawal)
+        {
+            return false;
+        }
+        // Withdrawals made within the window as of the next tick of the clock, like in apply
+        if let Some(rule) = self.policy.velocity_lock {
+            let recent = self
+                .recent_withdrawals
+                .iter()
+                .filter(|&&made_at| made_at + rule.window > self.clock + 1)
+                .count();
+            if recent >= rule.max_withdrawals {
+                return false;
+            }
+        }
+        let amount = match self.policy.rounding.mode(TransactionType::Withdrawal) {
+            Some(mode) => amount.rounded(mode),
+            None => amount,
+        };
+        self.covers(amount) || self.policy.allow_overdraft
+    }
+
+    /// Check that available and held funds add up to total and that held funds aren't negative
+    pub fn check_invariants(&self) -> TransactionResult<()> {
+        let balanced = (self.available.0 + self.held.0 - self.total.0).abs() < EPSILON;
+        if balanced && self.held.0 > -EPSILON {
+            Ok(())
+        } else {
+            Err(TransactionError::InvariantViolated(self.id))
+        }
+    }
+
+    /// Make the account follow given `policy` from now on
+    #[cfg(feature = "std")]
+    pub(crate) fn set_policy(&mut self, policy: AccountPolicy) {
+        self.policy = policy;
+    }
+
+    /// Get all the stored transactions along with whether they are currently disputed
+    ///
+    /// Only deposits and withdrawals are stored, in no particular order
+    pub fn history(&self) -> impl Iterator<Item = (TransactionId, &Transaction, bool)> {
+        self.tx_history
+            .iter()
+            .map(|(&id, tx)| (id, &tx.transaction, tx.disputed))
+    }
+
+    /// Serialize the account as json, leaving out its tx_history
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn json_without_history(&mut self) -> serde_json::Result<String> {
+        let tx_history = core::mem::take(&mut self.tx_history);
+        let json = serde_json::to_string(self);
+        self.tx_history = tx_history;
+        json
+    }
+
+    /// Get all the entries of tx_history
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn history_entries(
+        &self,
+    ) -> impl Iterator<Item = (&TransactionId, &DisputableTransaction)> {
+        self.tx_history.iter()
+    }
+
+    /// Get an entry of tx_history
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn history_entry(&self, tx: TransactionId) -> Option<&DisputableTransaction> {
+        self.tx_history.get(&tx)
+    }
+
+    /// Put back an entry of tx_history that was stored separately from the account
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn restore_history_entry(
+        &mut self,
+        tx: TransactionId,
+        entry: DisputableTransaction,
+    ) {
+        self.tx_history.insert(tx, entry);
+    }
+
+    /// Check if transaction with given id is stored in tx_history
+    #[cfg(feature = "std")]
+    pub(crate) fn has_tx(&self, tx: TransactionId) -> bool {
+        self.tx_history.contains_key(&tx)
+    }
+
+    /// Get a transaction stored in tx_history
+    #[cfg(feature = "std")]
+    pub(crate) fn transaction(&self, tx: TransactionId) -> Option<&Transaction> {
+        self.tx_history.get(&tx).map(|tx| &tx.transaction)
+    }
+
+    /// Get amounts of the transactions under dispute, which add up to held funds, ordered by id
+    pub fn held_breakdown(&self) -> Vec<(TransactionId, Amount)> {
+        let mut breakdown: Vec<_> = self
+            .tx_history
+            .iter()
+            .filter(|(_, tx)| tx.disputed)
+            .map(|(&id, tx)| (id, tx.transaction.amount))
+            .collect();
+        breakdown.sort_unstable_by_key(|&(id, _)| id);
+        breakdown
+    }
+
+    /// Check if transaction with given id is under dispute, `None` if it is not in tx_history
+    pub fn is_disputed(&self, tx: TransactionId) -> Option<bool> {
+        self.tx_history.get(&tx).map(|tx| tx.disputed)
+    }
+
+    /// Add `amount` to available funds bypassing transaction handling
+    #[cfg(any(feature = "std", test))]
+    pub(crate) fn credit(&mut self, amount: Amount) {
+        self.available += amount;
+        self.total += amount;
+    }
+
     /// Put a transaction into tx_history
-    pub fn save_tx(&mut self, tx: Transaction) -> TransactionResult<()> {
+    pub fn save_tx(&mut self, tx: &Transaction) -> TransactionResult<()> {
+        self.insert_tx(tx, false)
+    }
+
+    /// Put a transaction into tx_history, remembering if it overdrew the account
+    fn insert_tx(&mut self, tx: &Transaction, overdrew: bool) -> TransactionResult<()> {
         if self.tx_history.contains_key(&tx.tx) {
             return Err(TransactionError::AlreadyExist(tx.tx));
         }
         self.tx_history.insert(
             tx.tx,
             DisputableTransaction {
-                transaction: tx,
+                transaction: tx.clone(),
                 disputed: false,
+                charged_back: false,
+                overdrew,
+                made_at: self.clock,
             },
         );
         Ok(())
     }
 
-    /// Try to apply a transaction on user account
-    pub fn apply(&mut self, tx: Transaction) -> TransactionResult<()> {
-        if self.locked {
+    /// Try to apply a transaction on user account, returning how it changed the balances
+    pub fn apply_with_delta(&mut self, tx: &Transaction) -> TransactionResult<BalanceDelta> {
+        let (available, held, total, locked) = (self.available, self.held, self.total, self.locked);
+        self.apply(tx)?;
+        Ok(BalanceDelta {
+            available: self.available - available,
+            held: self.held - held,
+            total: self.total - total,
+            lock_changed: self.locked != locked,
+        })
+    }
+
+    /// Try to apply a transaction on user account, telling if it locked the account
+    pub fn apply(&mut self, tx: &Transaction) -> TransactionResult<TransactionOutcome> {
+        // Charged back transaction is final, so referencing it is an error on its own,
+        // regardless of the account being locked by the chargeback
+        if let TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback =
+            tx.r#type
+        {
+            if self.tx_history.get(&tx.tx).is_some_and(|t| t.charged_back) {
+                return Err(TransactionError::AlreadyChargedBack(tx.tx));
+            }
+        }
+        if self.locked && !self.policy.locked_account.permits(tx.r#type) {
             return Err(TransactionError::AccountLocked);
         }
-        match tx.r#type {
+        let was_locked = self.locked;
+        if self.policy.on_duplicate == DuplicatePolicy::ReplaceIfNotDisputed
+            && tx.is_monetary()
+            && self.tx_history.get(&tx.tx).is_some_and(|previous| {
+                previous.transaction.r#type == tx.r#type
+                    && !previous.disputed
+                    && !previous.charged_back
+            })
+        {
+            return self.replace_tx(tx);
+        }
+        self.clock += 1;
+        // Reused id is rejected up front, as balances are changed before saving the transaction
+        if tx.is_monetary() && self.tx_history.contains_key(&tx.tx) {
+            return Err(TransactionError::AlreadyExist(tx.tx));
+        }
+        let rounded;
+        let tx = match self.policy.rounding.mode(tx.r#type) {
+            Some(mode) => {
+                rounded = Transaction {
+                    amount: tx.amount.rounded(mode),
+                    ..tx.clone()
+                };
+                &rounded
+            }
+            None => tx,
+        };
+        let result = match tx.r#type {
             TransactionType::Deposit => {
+                if let Some(max_total) = self.policy.max_total {
+                    if self.total.0 + tx.amount.0 - max_total.0 > EPSILON {
+                        return Err(TransactionError::LimitExceeded(tx.tx));
+                    }
+                }
                 self.available += tx.amount;
                 self.total += tx.amount;
                 self.save_tx(tx)
             }
             TransactionType::Withdrawal => {
-                if self.available >= tx.amount {
+                if self.exceeds_velocity() {
+                    self.lock(LockReason::Velocity);
+                    return Err(TransactionError::VelocityExceeded(tx.tx));
+                }
+                self.recent_withdrawals.push_back(self.clock);
+                if self.covers(tx.amount) {
                     self.available -= tx.amount;
                     self.total -= tx.amount;
                     self.save_tx(tx)
+                } else if self.policy.allow_overdraft {
+                    self.available -= tx.amount;
+                    self.total -= tx.amount;
+                    self.insert_tx(tx, true)
+                } else if self.policy.partial_withdrawal && self.available > Amount(0.) {
+                    let filled = Transaction {
+                        amount: self.available,
+                        ..tx.clone()
+                    };
+                    self.total -= filled.amount;
+                    self.available = Amount(0.);
+                    self.save_tx(&filled)
                 } else {
-                    Err(TransactionError::UnsufficientFunds(tx))
+                    Err(TransactionError::UnsufficientFunds(tx.tx))
                 }
             }
             _ => self.handle_disputes(tx),
+        };
+        result?;
+        self.record_activity(tx);
+        Ok(if self.locked && !was_locked {
+            TransactionOutcome::AppliedLockedAccount
+        } else {
+            TransactionOutcome::Applied
+        })
+    }
+
+    /// Apply `tx` in place of the transaction of its id in history, see `DuplicatePolicy`
+    fn replace_tx(&mut self, tx: &Transaction) -> TransactionResult<TransactionOutcome> {
+        let Some(previous) = self.tx_history.remove(&tx.tx) else {
+            return Err(TransactionError::NotFound(tx.tx));
+        };
+        // Failed replacement leaves no trace, also of a velocity lock set by nested apply
+        let saved = (
+            self.available,
+            self.total,
+            self.transaction_count,
+            self.deposit_volume,
+            self.withdrawal_volume,
+            self.clock,
+            self.recent_withdrawals.clone(),
+            self.locked,
+            self.lock_reason,
+        );
+        // The older transaction is undone as if it never happened, including its activity
+        let amount = previous.transaction.amount;
+        match previous.transaction.r#type {
+            TransactionType::Deposit => {
+                self.available -= amount;
+                self.total -= amount;
+                self.deposit_volume -= amount;
+            }
+            _ => {
+                self.available += amount;
+                self.total += amount;
+                self.withdrawal_volume -= amount;
+                // So that a resent withdrawal doesn't count twice towards velocity limit
+                if let Some(index) = self
+                    .recent_withdrawals
+                    .iter()
+                    .position(|&made_at| made_at == previous.made_at)
+                {
+                    self.recent_withdrawals.remove(index);
+                }
+            }
+        }
+        self.transaction_count -= 1;
+        let negative_available =
+            self.policy.allow_negative_available || self.policy.allow_overdraft;
+        let result = self.apply(tx).and_then(|outcome| {
+            if self.available < Amount(0.) && !negative_available {
+                Err(TransactionError::UnsufficientFunds(tx.tx))
+            } else {
+                Ok(outcome)
+            }
+        });
+        if result.is_err() {
+            (
+                self.available,
+                self.total,
+                self.transaction_count,
+                self.deposit_volume,
+                self.withdrawal_volume,
+                self.clock,
+                self.recent_withdrawals,
+                self.locked,
+                self.lock_reason,
+            ) = saved;
+            self.tx_history.insert(tx.tx, previous);
+        }
+        result
+    }
+
+    /// Count an applied transaction, adding the amount it moved to the volumes
+    fn record_activity(&mut self, tx: &Transaction) {
+        self.transaction_count += 1;
+        // Amount is taken from history, as a partial withdrawal moves less than requested
+        let amount = self
+            .tx_history
+            .get(&tx.tx)
+            .map(|entry| entry.transaction.amount);
+        match (tx.r#type, amount) {
+            (TransactionType::Deposit, Some(amount)) => self.deposit_volume += amount,
+            (TransactionType::Withdrawal, Some(amount)) => self.withdrawal_volume += amount,
+            _ => (),
+        }
+    }
+
+    /// Sum amounts of deposits in history that weren't charged back
+    fn deposited(&self) -> Amount {
+        self.tx_history
+            .values()
+            .filter(|tx| tx.transaction.r#type == TransactionType::Deposit && !tx.charged_back)
+            .map(|tx| tx.transaction.amount)
+            .sum()
+    }
+
+    /// Check that `held` funds of an account after a dispute or resolve stay within `0..=total`
+    ///
+    /// Held funds may exceed total only if the policy lets available funds go negative.
+    /// Takes the fields it needs, as it is called while a transaction of history is borrowed.
+    fn check_held(
+        id: ClientId,
+        policy: &AccountPolicy,
+        held: f64,
+        total: f64,
+    ) -> TransactionResult<()> {
+        let negative_available = policy.allow_negative_available || policy.allow_overdraft;
+        if held < -EPSILON || (held - total > EPSILON && !negative_available) {
+            Err(TransactionError::InvariantViolated(id))
+        } else {
+            Ok(())
         }
     }
 
     /// Handle disputing, resolving and charging back deposits and withdrawals
-    fn handle_disputes(&mut self, current_tx: Transaction) -> TransactionResult<()> {
+    fn handle_disputes(&mut self, current_tx: &Transaction) -> TransactionResult<()> {
+        // Deposits are summed up front, as history can't be iterated once its entry is borrowed
+        let deposited = (current_tx.r#type == TransactionType::Dispute
+            && self.policy.check_withdrawal_disputes)
+            .then(|| self.deposited());
         let disputable_tx = if let Some(disputable_tx) = self.tx_history.get_mut(&current_tx.tx) {
             disputable_tx
         } else {
             return Err(TransactionError::NotFound(current_tx.tx));
         };
+        if disputable_tx.transaction.client != current_tx.client {
+            return Err(TransactionError::ClientMismatch(current_tx.tx));
+        }
         // Do nothing when disputing already disputed transaction
         // or resolving / charging back not disputed transaction
         match (disputable_tx.disputed, current_tx.r#type) {
@@ -140,60 +953,121 @@ impl Account {
             TransactionType::Deposit => match current_tx.r#type {
                 TransactionType::Dispute => {
                     // When disputing a deposit transaction, check if client
-                    // hasn't already withdrawn what he want to charge back
-                    if self.available < disputable_tx.transaction.amount {
-                        return Err(TransactionError::UnsufficientFunds(current_tx));
+                    // hasn't already withdrawn what he want to charge back,
+                    // unless policy allows available funds to go negative
+                    if self.available < disputable_tx.transaction.amount
+                        && !self.policy.allow_negative_available
+                    {
+                        return Err(TransactionError::UnsufficientFunds(current_tx.tx));
                     }
-                    self.available -= disputable_tx.transaction.amount;
-                    self.held += disputable_tx.transaction.amount;
+                    let amount = disputable_tx.transaction.amount;
+                    Self::check_held(self.id, &self.policy, self.held.0 + amount.0, self.total.0)?;
+                    self.available -= amount;
+                    self.held += amount;
                     disputable_tx.disputed = true;
                 }
                 TransactionType::Resolve => {
-                    self.available += disputable_tx.transaction.amount;
-                    self.held -= disputable_tx.transaction.amount;
+                    // Funds may only be released if they are still held,
+                    // otherwise the invariants of the account would be broken
+                    let amount = disputable_tx.transaction.amount;
+                    Self::check_held(self.id, &self.policy, self.held.0 - amount.0, self.total.0)?;
+                    self.available += amount;
+                    self.held -= amount;
                     disputable_tx.disputed = false;
                 }
+                // Charging back deposit takes the held amount off held and total funds,
+                // available funds stay as they are, also when negative as allowed by policy
                 TransactionType::Chargeback => {
-                    self.total -= disputable_tx.transaction.amount;
-                    self.held -= disputable_tx.transaction.amount;
-                    self.locked = true;
+                    let amount = disputable_tx.transaction.amount;
+                    Self::check_held(
+                        self.id,
+                        &self.policy,
+                        self.held.0 - amount.0,
+                        self.total.0 - amount.0,
+                    )?;
+                    self.total -= amount;
+                    self.held -= amount;
+                    disputable_tx.disputed = false;
+                    disputable_tx.charged_back = true;
+                    self.lock(LockReason::Chargeback);
                 }
                 // Excluded back in apply
-                _ => unreachable!(),
+                _ => return Err(TransactionError::CorruptHistory(current_tx.tx)),
             },
             // For dealing with withdrawals the following assumptions were made
             TransactionType::Withdrawal => match current_tx.r#type {
                 // Disputing withdrawal:
                 // - held and total should increase by a previously withdrawn amount
                 // - available amount shouldn't change
+                // For a withdrawal that overdrew the account, available stays negative and
+                // the whole withdrawn amount is held, so that a chargeback brings available
+                // back to where it was before the withdrawal.
                 TransactionType::Dispute => {
-                    self.total += disputable_tx.transaction.amount;
-                    self.held += disputable_tx.transaction.amount;
+                    if disputable_tx.overdrew && !self.policy.dispute_overdrawn_withdrawals {
+                        return Err(TransactionError::OverdrawnWithdrawal(current_tx.tx));
+                    }
+                    let amount = disputable_tx.transaction.amount;
+                    // Withdrawn funds that are back on the account can't be held again
+                    if let Some(deposited) = deposited {
+                        if self.total.0 + amount.0 - deposited.0 > EPSILON {
+                            return Err(TransactionError::FundsRestored(current_tx.tx));
+                        }
+                    }
+                    Self::check_held(
+                        self.id,
+                        &self.policy,
+                        self.held.0 + amount.0,
+                        self.total.0 + amount.0,
+                    )?;
+                    self.total += amount;
+                    self.held += amount;
                     disputable_tx.disputed = true;
                 }
                 // Resolving withdrawal
                 // - held and total should decrease by the amount no longer disputed
                 // - available amount shouldn't change
                 TransactionType::Resolve => {
-                    self.total -= disputable_tx.transaction.amount;
-                    self.held -= disputable_tx.transaction.amount;
+                    let amount = disputable_tx.transaction.amount;
+                    Self::check_held(
+                        self.id,
+                        &self.policy,
+                        self.held.0 - amount.0,
+                        self.total.0 - amount.0,
+                    )?;
+                    self.total -= amount;
+                    self.held -= amount;
                     disputable_tx.disputed = false;
                 }
                 // Charging back withdrawal:
                 // - available should increase by the amount disputed
                 // - held should decrease by the amount disputed
                 // - total shouldn't change
+                // The whole amount is returned regardless of policies, the funds were held
+                // by the dispute. Available funds made negative by an overdraft or a dispute
+                // of a deposit go up by the amount as well, they may stay negative.
                 TransactionType::Chargeback => {
-                    self.available += disputable_tx.transaction.amount;
-                    self.held -= disputable_tx.transaction.amount;
-                    self.locked = true;
+                    let amount = disputable_tx.transaction.amount;
+                    Self::check_held(self.id, &self.policy, self.held.0 - amount.0, self.total.0)?;
+                    self.available += amount;
+                    self.held -= amount;
+                    disputable_tx.disputed = false;
+                    disputable_tx.charged_back = true;
+                    self.lock(LockReason::Chargeback);
                 }
                 // Excluded back in apply
-                _ => unreachable!(),
+                _ => return Err(TransactionError::CorruptHistory(current_tx.tx)),
             },
-            // Only deposit and withdrawal transactions are stored in history
-            _ => unreachable!(),
+            // Only deposit and withdrawal transactions are stored in history by apply,
+            // anything else got there through save_tx or a corrupted snapshot
+            _ => return Err(TransactionError::CorruptHistory(current_tx.tx)),
         }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            client = self.id,
+            tx = current_tx.tx,
+            transition = ?current_tx.r#type,
+            "Dispute state changed"
+        );
         Ok(())
     }
 }
@@ -201,58 +1075,63 @@ impl Account {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testutil::{chargeback, deposit, dispute, resolve, withdrawal};
 
-    fn deposit(amount: f64, tx: TransactionId) -> Transaction {
-        Transaction {
-            r#type: TransactionType::Deposit,
-            amount: Amount(amount),
-            tx,
-            client: 0,
-        }
-    }
-
-    fn withdrawal(amount: f64, tx: TransactionId) -> Transaction {
-        Transaction {
-            r#type: TransactionType::Withdrawal,
-            amount: Amount(amount),
-            tx,
-            client: 0,
-        }
-    }
-
-    fn dispute(tx: TransactionId) -> Transaction {
-        Transaction {
-            r#type: TransactionType::Dispute,
-            tx,
-            client: 0,
-            amount: Amount(0.),
-        }
-    }
-
-    fn resolve(tx: TransactionId) -> Transaction {
-        Transaction {
-            r#type: TransactionType::Resolve,
-            tx,
-            client: 0,
-            amount: Amount(0.),
-        }
-    }
+    #[test]
+    fn each_error_should_have_its_own_code() {
+        let errors = [
+            (
+                TransactionError::UnsufficientFunds(1),
+                "E_INSUFFICIENT_FUNDS",
+            ),
+            (TransactionError::AccountLocked, "E_ACCOUNT_LOCKED"),
+            (TransactionError::AlreadyDisputed(1), "E_ALREADY_DISPUTED"),
+            (TransactionError::NotDisputed(1), "E_NOT_DISPUTED"),
+            (TransactionError::NotFound(1), "E_NOT_FOUND"),
+            (TransactionError::AlreadyExist(1), "E_ALREADY_EXISTS"),
+            (
+                TransactionError::AlreadyChargedBack(1),
+                "E_ALREADY_CHARGED_BACK",
+            ),
+            (TransactionError::ClientMismatch(1), "E_CLIENT_MISMATCH"),
+            (
+                TransactionError::InvariantViolated(1),
+                "E_INVARIANT_VIOLATED",
+            ),
+            (
+                TransactionError::OverdrawnWithdrawal(1),
+                "E_OVERDRAWN_WITHDRAWAL",
+            ),
+            (TransactionError::VelocityExceeded(1), "E_VELOCITY_EXCEEDED"),
+            (TransactionError::FundsRestored(1), "E_FUNDS_RESTORED"),
+            (TransactionError::OutOfOrder(1), "E_OUT_OF_ORDER"),
+            (TransactionError::CorruptHistory(1), "E_CORRUPT_HISTORY"),
+            (TransactionError::UnknownClient(1), "E_UNKNOWN_CLIENT"),
+            (TransactionError::LimitExceeded(1), "E_LIMIT_EXCEEDED"),
+            (
+                TransactionError::DestinationUnavailable(1),
+                "E_DESTINATION_UNAVAILABLE",
+            ),
+            #[cfg(feature = "std")]
+            (
+                TransactionError::Storage(StoreError::Json(
+                    serde_json::from_str::<()>("x").unwrap_err(),
+                )),
+                "E_STORAGE",
+            ),
+        ];
 
-    fn chargeback(tx: TransactionId) -> Transaction {
-        Transaction {
-            r#type: TransactionType::Chargeback,
-            tx,
-            client: 0,
-            amount: Amount(0.),
+        for (error, code) in errors {
+            assert_eq!(code, error.code(), "code of {}", error.name());
         }
     }
 
     #[test]
     fn transaction_id_should_be_unique() {
         let mut account = Account::default();
-        account.apply(deposit(5., 0)).unwrap();
+        account.apply(&deposit(0, 0, 5.)).unwrap();
 
-        assert!(account.apply(deposit(5., 0)).is_err());
+        assert!(account.apply(&deposit(0, 0, 5.)).is_err());
     }
 
     #[test]
@@ -264,7 +1143,7 @@ mod tests {
             ..Default::default()
         };
 
-        account.apply(withdrawal(4., 0)).unwrap();
+        account.apply(&withdrawal(0, 0, 4.)).unwrap();
 
         assert_eq!(account.total, Amount(1.));
         assert_eq!(account.available, Amount(1.));
@@ -278,25 +1157,25 @@ mod tests {
             ..Default::default()
         };
 
-        assert!(account.apply(withdrawal(5., 0)).is_err());
+        assert!(account.apply(&withdrawal(0, 0, 5.)).is_err());
     }
 
     #[test]
     fn dispute_to_already_disputed_tx_should_fail() {
         let mut account = Account::default();
-        account.apply(deposit(5., 0)).unwrap();
+        account.apply(&deposit(0, 0, 5.)).unwrap();
 
-        account.apply(dispute(0)).unwrap();
+        account.apply(&dispute(0, 0)).unwrap();
 
-        assert!(account.apply(dispute(0)).is_err());
+        assert!(account.apply(&dispute(0, 0)).is_err());
     }
 
     #[test]
     fn dispute_to_deposit_should_freeze_funds() {
         let mut account = Account::default();
-        account.apply(deposit(5., 0)).unwrap();
+        account.apply(&deposit(0, 0, 5.)).unwrap();
 
-        account.apply(dispute(0)).unwrap();
+        account.apply(&dispute(0, 0)).unwrap();
 
         assert_eq!(account.total, Amount(5.));
         assert_eq!(account.available, Amount(0.));
@@ -306,10 +1185,34 @@ mod tests {
     #[test]
     fn dispute_to_deposit_should_not_work_if_funds_already_withdrawn() {
         let mut account = Account::default();
-        account.apply(deposit(5., 0)).unwrap();
-        account.apply(withdrawal(4., 1)).unwrap();
+        account.apply(&deposit(0, 0, 5.)).unwrap();
+        account.apply(&withdrawal(0, 1, 4.)).unwrap();
 
-        assert!(account.apply(dispute(0)).is_err());
+        assert!(matches!(
+            account.apply(&dispute(0, 0)),
+            Err(TransactionError::UnsufficientFunds(0))
+        ));
+        assert_eq!(account.available, Amount(1.));
+        assert_eq!(account.held, Amount(0.));
+    }
+
+    #[test]
+    fn dispute_to_partly_withdrawn_deposit_should_hold_funds_if_negative_available_allowed() {
+        let mut account = Account::with_policy(
+            0,
+            AccountPolicy {
+                allow_negative_available: true,
+                ..AccountPolicy::default()
+            },
+        );
+        account.apply(&deposit(0, 0, 5.)).unwrap();
+        account.apply(&withdrawal(0, 1, 4.)).unwrap();
+
+        account.apply(&dispute(0, 0)).unwrap();
+
+        assert_eq!(account.total, Amount(1.));
+        assert_eq!(account.available, Amount(-4.));
+        assert_eq!(account.held, Amount(5.));
     }
 
     #[test]
@@ -319,9 +1222,9 @@ mod tests {
             total: Amount(5.),
             ..Account::default()
         };
-        account.apply(withdrawal(5., 0)).unwrap();
+        account.apply(&withdrawal(0, 0, 5.)).unwrap();
 
-        account.apply(dispute(0)).unwrap();
+        account.apply(&dispute(0, 0)).unwrap();
 
         assert_eq!(account.total, Amount(5.));
         assert_eq!(account.available, Amount(0.));
@@ -331,24 +1234,144 @@ mod tests {
     #[test]
     fn resolving_and_charging_back_on_not_disputed_tx_should_fail() {
         let mut account = Account::default();
-        account.apply(deposit(5., 0)).unwrap();
+        account.apply(&deposit(0, 0, 5.)).unwrap();
 
-        assert!(account.apply(resolve(0)).is_err());
-        assert!(account.apply(chargeback(0)).is_err());
+        assert!(account.apply(&resolve(0, 0)).is_err());
+        assert!(account.apply(&chargeback(0, 0)).is_err());
     }
 
     #[test]
     fn resolving_disputed_deposit_should_revert_dispute() {
         let mut account = Account::default();
-        account.apply(deposit(5., 0)).unwrap();
-        account.apply(dispute(0)).unwrap();
+        account.apply(&deposit(0, 0, 5.)).unwrap();
+        account.apply(&dispute(0, 0)).unwrap();
 
-        account.apply(resolve(0)).unwrap();
+        account.apply(&resolve(0, 0)).unwrap();
 
         assert_eq!(account.total, Amount(5.));
         assert_eq!(account.available, Amount(5.));
         assert_eq!(account.held, Amount(0.));
-        assert_eq!(account.tx_history[&0].disputed, false);
+        assert!(!account.tx_history[&0].disputed);
+    }
+
+    #[test]
+    fn resolving_dispute_after_withdrawing_remaining_funds_should_release_held() {
+        let mut account = Account::default();
+        account.apply(&deposit(0, 0, 10.)).unwrap();
+        account.apply(&deposit(0, 1, 5.)).unwrap();
+        account.apply(&dispute(0, 0)).unwrap();
+        account.apply(&withdrawal(0, 2, 5.)).unwrap();
+
+        account.apply(&resolve(0, 0)).unwrap();
+
+        assert_eq!(account.total, Amount(10.));
+        assert_eq!(account.available, Amount(10.));
+        assert_eq!(account.held, Amount(0.));
+        account.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn withdrawal_should_only_use_funds_released_by_resolve() {
+        let mut account = Account::default();
+        account.apply(&deposit(0, 0, 10.)).unwrap();
+        account.apply(&dispute(0, 0)).unwrap();
+
+        assert!(matches!(
+            account.apply(&withdrawal(0, 1, 4.)),
+            Err(TransactionError::UnsufficientFunds(1))
+        ));
+        assert_eq!(account.available, Amount(0.));
+        assert_eq!(account.held, Amount(10.));
+        assert_eq!(account.total, Amount(10.));
+        account.check_invariants().unwrap();
+
+        account.apply(&resolve(0, 0)).unwrap();
+        assert_eq!(account.available, Amount(10.));
+        assert_eq!(account.held, Amount(0.));
+
+        // Rejected withdrawal leaves no trace in history, so its id may be used again
+        account.apply(&withdrawal(0, 1, 4.)).unwrap();
+        assert_eq!(account.available, Amount(6.));
+        assert_eq!(account.held, Amount(0.));
+        assert_eq!(account.total, Amount(6.));
+        account.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn deposit_with_funds_available_should_be_voided() {
+        let mut account = Account::default();
+        account.apply(&deposit(0, 1, 5.)).unwrap();
+        account.apply(&deposit(0, 2, 3.)).unwrap();
+
+        assert_eq!(Amount(5.), account.void_deposit(1).unwrap());
+
+        assert_eq!(account.available, Amount(3.));
+        assert_eq!(account.total, Amount(3.));
+        assert!(!account.tx_history.contains_key(&1));
+        assert_eq!(account.transaction_count(), 1);
+        assert_eq!(account.deposit_volume(), Amount(3.));
+        account.check_invariants().unwrap();
+        account.apply(&deposit(0, 1, 1.)).unwrap();
+    }
+
+    #[test]
+    fn id_freed_by_voiding_should_be_reused_with_fresh_dispute_state() {
+        let mut account = Account::default();
+        account.apply(&deposit(0, 1, 5.)).unwrap();
+        account.apply(&dispute(0, 1)).unwrap();
+        account.apply(&resolve(0, 1)).unwrap();
+        account.void_deposit(1).unwrap();
+
+        account.apply(&deposit(0, 1, 2.)).unwrap();
+        account.apply(&dispute(0, 1)).unwrap();
+
+        assert_eq!(account.held, Amount(2.));
+        assert_eq!(account.available, Amount(0.));
+        assert_eq!(account.held_breakdown(), vec![(1, Amount(2.))]);
+        account.apply(&chargeback(0, 1)).unwrap();
+        assert_eq!(account.total, Amount(0.));
+        account.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn deposit_with_funds_spent_should_not_be_voided() {
+        let mut account = Account::default();
+        account.apply(&deposit(0, 1, 5.)).unwrap();
+        account.apply(&withdrawal(0, 2, 1.)).unwrap();
+        account.apply(&deposit(0, 3, 2.)).unwrap();
+        account.apply(&dispute(0, 3)).unwrap();
+
+        assert!(matches!(
+            account.void_deposit(1),
+            Err(TransactionError::UnsufficientFunds(1))
+        ));
+        assert!(matches!(
+            account.void_deposit(3),
+            Err(TransactionError::AlreadyDisputed(3))
+        ));
+        assert!(matches!(
+            account.void_deposit(2),
+            Err(TransactionError::NotFound(2))
+        ));
+        assert_eq!(account.available, Amount(4.));
+        assert_eq!(account.total, Amount(6.));
+        assert!(account.tx_history.contains_key(&1));
+    }
+
+    #[test]
+    fn resolving_dispute_with_funds_no_longer_held_should_fail() {
+        let mut account = Account::default();
+        account.apply(&deposit(0, 0, 10.)).unwrap();
+        account.apply(&dispute(0, 0)).unwrap();
+        account.held = Amount(4.);
+
+        assert!(matches!(
+            account.apply(&resolve(0, 0)),
+            Err(TransactionError::InvariantViolated(0))
+        ));
+        assert_eq!(account.available, Amount(0.));
+        assert_eq!(account.held, Amount(4.));
+        assert!(account.tx_history[&0].disputed);
     }
 
     #[test]
@@ -358,29 +1381,30 @@ mod tests {
             total: Amount(5.),
             ..Account::default()
         };
-        account.apply(withdrawal(5., 0)).unwrap();
-        account.apply(dispute(0)).unwrap();
+        account.apply(&withdrawal(0, 0, 5.)).unwrap();
+        account.apply(&dispute(0, 0)).unwrap();
 
-        account.apply(resolve(0)).unwrap();
+        account.apply(&resolve(0, 0)).unwrap();
 
         assert_eq!(account.total, Amount(0.));
         assert_eq!(account.available, Amount(0.));
         assert_eq!(account.held, Amount(0.));
-        assert_eq!(account.tx_history[&0].disputed, false);
+        assert!(!account.tx_history[&0].disputed);
     }
 
     #[test]
     fn charging_back_disputed_deposit_should_revert_transaction() {
         let mut account = Account::default();
-        account.apply(deposit(5., 0)).unwrap();
-        account.apply(dispute(0)).unwrap();
+        account.apply(&deposit(0, 0, 5.)).unwrap();
+        account.apply(&dispute(0, 0)).unwrap();
 
-        account.apply(chargeback(0)).unwrap();
+        account.apply(&chargeback(0, 0)).unwrap();
 
         assert_eq!(account.total, Amount(0.));
         assert_eq!(account.available, Amount(0.));
         assert_eq!(account.held, Amount(0.));
-        assert_eq!(account.locked, true);
+        assert!(account.locked);
+        assert_eq!(account.lock_reason(), Some(LockReason::Chargeback));
     }
 
     #[test]
@@ -390,15 +1414,241 @@ mod tests {
             total: Amount(5.),
             ..Account::default()
         };
-        account.apply(withdrawal(5., 0)).unwrap();
-        account.apply(dispute(0)).unwrap();
+        account.apply(&withdrawal(0, 0, 5.)).unwrap();
+        account.apply(&dispute(0, 0)).unwrap();
 
-        account.apply(chargeback(0)).unwrap();
+        account.apply(&chargeback(0, 0)).unwrap();
 
         assert_eq!(account.total, Amount(5.));
         assert_eq!(account.available, Amount(5.));
         assert_eq!(account.held, Amount(0.));
-        assert_eq!(account.locked, true);
+        assert!(account.locked);
+    }
+
+    #[test]
+    fn charging_back_withdrawal_should_return_funds_under_both_negative_available_settings() {
+        let mut strict = Account::default();
+        strict.apply(&deposit(0, 1, 10.)).unwrap();
+        strict.apply(&withdrawal(0, 2, 6.)).unwrap();
+        assert!(strict.apply(&dispute(0, 1)).is_err());
+        strict.apply(&dispute(0, 2)).unwrap();
+        strict.apply(&chargeback(0, 2)).unwrap();
+
+        assert_eq!(strict.available, Amount(10.));
+        assert_eq!(strict.held, Amount(0.));
+        assert_eq!(strict.total, Amount(10.));
+        strict.check_invariants().unwrap();
+
+        let mut lenient = Account::with_policy(
+            0,
+            AccountPolicy {
+                allow_negative_available: true,
+                ..AccountPolicy::default()
+            },
+        );
+        lenient.apply(&deposit(0, 1, 10.)).unwrap();
+        lenient.apply(&withdrawal(0, 2, 6.)).unwrap();
+        lenient.apply(&dispute(0, 1)).unwrap();
+        lenient.apply(&dispute(0, 2)).unwrap();
+        assert_eq!(lenient.available, Amount(-6.));
+        lenient.apply(&chargeback(0, 2)).unwrap();
+
+        assert_eq!(lenient.available, Amount(0.));
+        assert_eq!(lenient.held, Amount(10.));
+        assert_eq!(lenient.total, Amount(10.));
+        lenient.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn withdrawal_with_unsufficient_funds_should_overdraw_if_allowed() {
+        let mut account = Account {
+            available: Amount(2.),
+            total: Amount(2.),
+            policy: AccountPolicy {
+                allow_overdraft: true,
+                ..AccountPolicy::default()
+            },
+            ..Account::default()
+        };
+
+        account.apply(&withdrawal(0, 0, 5.)).unwrap();
+
+        assert_eq!(account.total, Amount(-3.));
+        assert_eq!(account.available, Amount(-3.));
+        assert!(account.tx_history[&0].overdrew);
+    }
+
+    #[test]
+    fn dispute_to_overdrawn_withdrawal_should_hold_withdrawn_amount_once() {
+        let mut account = Account {
+            available: Amount(2.),
+            total: Amount(2.),
+            policy: AccountPolicy {
+                allow_overdraft: true,
+                ..AccountPolicy::default()
+            },
+            ..Account::default()
+        };
+        account.apply(&withdrawal(0, 0, 5.)).unwrap();
+
+        account.apply(&dispute(0, 0)).unwrap();
+
+        assert_eq!(account.total, Amount(2.));
+        assert_eq!(account.available, Amount(-3.));
+        assert_eq!(account.held, Amount(5.));
+
+        account.apply(&chargeback(0, 0)).unwrap();
+
+        assert_eq!(account.total, Amount(2.));
+        assert_eq!(account.available, Amount(2.));
+        assert_eq!(account.held, Amount(0.));
+    }
+
+    #[test]
+    fn dispute_to_overdrawn_withdrawal_should_fail_if_not_allowed() {
+        let mut account = Account {
+            available: Amount(2.),
+            total: Amount(2.),
+            policy: AccountPolicy {
+                allow_overdraft: true,
+                dispute_overdrawn_withdrawals: false,
+                ..AccountPolicy::default()
+            },
+            ..Account::default()
+        };
+        account.apply(&withdrawal(0, 0, 1.)).unwrap();
+        account.apply(&withdrawal(0, 1, 5.)).unwrap();
+
+        account.apply(&dispute(0, 0)).unwrap();
+        assert!(matches!(
+            account.apply(&dispute(0, 1)),
+            Err(TransactionError::OverdrawnWithdrawal(1))
+        ));
+        assert_eq!(account.held, Amount(1.));
+    }
+
+    #[test]
+    fn dispute_to_resolved_tx_should_be_allowed() {
+        let mut account = Account::default();
+        account.apply(&deposit(0, 0, 5.)).unwrap();
+        account.apply(&dispute(0, 0)).unwrap();
+        account.apply(&resolve(0, 0)).unwrap();
+
+        account.apply(&dispute(0, 0)).unwrap();
+
+        assert_eq!(account.available, Amount(0.));
+        assert_eq!(account.held, Amount(5.));
+        assert!(account.tx_history[&0].disputed);
+    }
+
+    #[test]
+    fn second_chargeback_should_report_already_charged_back() {
+        let mut account = Account::default();
+        account.apply(&deposit(0, 0, 5.)).unwrap();
+        account.apply(&withdrawal(0, 1, 2.)).unwrap();
+        account.apply(&dispute(0, 1)).unwrap();
+        account.apply(&chargeback(0, 1)).unwrap();
+
+        assert!(matches!(
+            account.apply(&chargeback(0, 1)),
+            Err(TransactionError::AlreadyChargedBack(1))
+        ));
+        assert_eq!(account.available, Amount(5.));
+        assert_eq!(account.held, Amount(0.));
+        assert_eq!(account.lock_reason(), Some(LockReason::Chargeback));
+    }
+
+    #[test]
+    fn dispute_to_charged_back_tx_should_fail() {
+        let mut account = Account::default();
+        account.apply(&deposit(0, 0, 5.)).unwrap();
+        account.apply(&dispute(0, 0)).unwrap();
+        account.apply(&chargeback(0, 0)).unwrap();
+
+        assert!(matches!(
+            account.apply(&dispute(0, 0)),
+            Err(TransactionError::AlreadyChargedBack(0))
+        ));
+        assert!(account.tx_history[&0].charged_back);
+        assert!(!account.tx_history[&0].disputed);
+    }
+
+    #[test]
+    fn invariants_should_hold_through_dispute_cycle() {
+        let mut account = Account::default();
+        account.apply(&deposit(0, 0, 5.1234)).unwrap();
+        account.apply(&withdrawal(0, 1, 1.0001)).unwrap();
+        account.apply(&dispute(0, 1)).unwrap();
+        account.check_invariants().unwrap();
+        account.apply(&resolve(0, 1)).unwrap();
+        account.check_invariants().unwrap();
+
+        account.held = Amount(1.);
+
+        assert!(account.check_invariants().is_err());
+    }
+
+    #[test]
+    fn dispute_from_different_client_should_fail() {
+        let mut account = Account::default();
+        account.apply(&deposit(0, 0, 5.)).unwrap();
+
+        let mut foreign_dispute = dispute(0, 0);
+        foreign_dispute.client = 1;
+
+        assert!(matches!(
+            account.apply(&foreign_dispute),
+            Err(TransactionError::ClientMismatch(0))
+        ));
+        assert_eq!(account.held, Amount(0.));
+    }
+
+    fn velocity_limited_account() -> Account {
+        Account::with_policy(
+            0,
+            AccountPolicy {
+                velocity_lock: Some(VelocityRule {
+                    max_withdrawals: 2,
+                    window: 3,
+                }),
+                ..AccountPolicy::default()
+            },
+        )
+    }
+
+    #[test]
+    fn withdrawals_exceeding_velocity_should_lock_account() {
+        let mut account = velocity_limited_account();
+        account.apply(&deposit(0, 0, 10.)).unwrap();
+        account.apply(&withdrawal(0, 1, 1.)).unwrap();
+        account.apply(&withdrawal(0, 2, 1.)).unwrap();
+
+        assert!(matches!(
+            account.apply(&withdrawal(0, 3, 1.)),
+            Err(TransactionError::VelocityExceeded(3))
+        ));
+        assert!(account.locked);
+        assert_eq!(account.lock_reason(), Some(LockReason::Velocity));
+        assert_eq!(account.available, Amount(8.));
+        assert!(matches!(
+            account.apply(&deposit(0, 4, 1.)),
+            Err(TransactionError::AccountLocked)
+        ));
+    }
+
+    #[test]
+    fn withdrawals_spread_over_window_should_not_lock_account() {
+        let mut account = velocity_limited_account();
+        account.apply(&deposit(0, 0, 10.)).unwrap();
+        account.apply(&withdrawal(0, 1, 1.)).unwrap();
+        account.apply(&withdrawal(0, 2, 1.)).unwrap();
+        account.apply(&deposit(0, 3, 1.)).unwrap();
+        account.apply(&deposit(0, 4, 1.)).unwrap();
+
+        account.apply(&withdrawal(0, 5, 1.)).unwrap();
+
+        assert!(!account.locked);
+        assert_eq!(account.available, Amount(9.));
     }
 
     #[test]
@@ -408,8 +1658,631 @@ mod tests {
             ..Account::default()
         };
 
-        assert!(account.apply(deposit(555., 0)).is_err());
-        assert!(account.apply(withdrawal(111., 0)).is_err());
-        assert!(account.apply(dispute(0)).is_err());
+        assert!(account.apply(&deposit(0, 0, 555.)).is_err());
+        assert!(account.apply(&withdrawal(0, 0, 111.)).is_err());
+        assert!(account.apply(&dispute(0, 0)).is_err());
+    }
+
+    #[test]
+    fn unlocked_account_should_accept_transactions() {
+        let mut account = Account::default();
+        account.apply(&deposit(0, 0, 5.)).unwrap();
+        account.apply(&dispute(0, 0)).unwrap();
+        account.apply(&chargeback(0, 0)).unwrap();
+        assert!(account.locked());
+
+        account.unlock();
+
+        assert!(!account.locked());
+        assert_eq!(account.lock_reason(), None);
+        account.apply(&deposit(0, 1, 3.)).unwrap();
+        assert_eq!(account.available, Amount(3.));
+        assert!(matches!(
+            account.apply(&dispute(0, 0)),
+            Err(TransactionError::AlreadyChargedBack(0))
+        ));
+    }
+
+    #[test]
+    fn disputed_state_should_follow_dispute_and_resolve() {
+        let mut account = Account::default();
+        account.apply(&deposit(0, 0, 5.)).unwrap();
+        assert_eq!(Some(false), account.is_disputed(0));
+
+        account.apply(&dispute(0, 0)).unwrap();
+        assert_eq!(Some(true), account.is_disputed(0));
+
+        account.apply(&resolve(0, 0)).unwrap();
+        assert_eq!(Some(false), account.is_disputed(0));
+        assert_eq!(None, account.is_disputed(1));
+    }
+
+    #[test]
+    fn dispute_should_not_hold_more_than_total() {
+        let mut account = Account::default();
+        account.apply(&deposit(0, 0, 5.)).unwrap();
+        account.apply(&withdrawal(0, 1, 5.)).unwrap();
+        // Account brought to a negative balance outside of transaction handling
+        account.credit(Amount(-1.));
+
+        assert!(matches!(
+            account.apply(&dispute(0, 1)),
+            Err(TransactionError::InvariantViolated(0))
+        ));
+        assert_eq!(account.held, Amount(0.));
+        assert_eq!(account.total, Amount(-1.));
+        assert_eq!(Some(false), account.is_disputed(1));
+    }
+
+    #[test]
+    fn resolve_should_not_leave_held_negative() {
+        let mut account = Account::default();
+        account.apply(&deposit(0, 0, 5.)).unwrap();
+        account.apply(&withdrawal(0, 1, 2.)).unwrap();
+        account.apply(&dispute(0, 1)).unwrap();
+        // Held funds taken away outside of transaction handling
+        account.held = Amount(1.);
+        account.total = Amount(4.);
+
+        assert!(matches!(
+            account.apply(&resolve(0, 1)),
+            Err(TransactionError::InvariantViolated(0))
+        ));
+        assert_eq!(account.held, Amount(1.));
+        assert_eq!(Some(true), account.is_disputed(1));
+    }
+
+    #[test]
+    fn history_should_list_stored_transactions() {
+        let mut account = Account::default();
+        account.apply(&deposit(0, 0, 5.)).unwrap();
+        account.apply(&withdrawal(0, 1, 2.)).unwrap();
+        account.apply(&withdrawal(0, 2, 9.)).unwrap_err();
+        account.apply(&dispute(0, 1)).unwrap();
+
+        let mut history: Vec<_> = account
+            .history()
+            .map(|(id, tx, disputed)| (id, tx.r#type, tx.amount, disputed))
+            .collect();
+        history.sort_by_key(|&(id, ..)| id);
+
+        assert_eq!(
+            vec![
+                (0, TransactionType::Deposit, Amount(5.), false),
+                (1, TransactionType::Withdrawal, Amount(2.), true),
+            ],
+            history
+        );
+    }
+
+    #[test]
+    fn delta_should_describe_changes_of_each_transaction_type() {
+        let delta = |available, held, total, lock_changed| BalanceDelta {
+            available: Amount(available),
+            held: Amount(held),
+            total: Amount(total),
+            lock_changed,
+        };
+        let mut account = Account::default();
+
+        assert_eq!(
+            delta(5., 0., 5., false),
+            account.apply_with_delta(&deposit(0, 0, 5.)).unwrap()
+        );
+        assert_eq!(
+            delta(-2., 0., -2., false),
+            account.apply_with_delta(&withdrawal(0, 1, 2.)).unwrap()
+        );
+        assert_eq!(
+            delta(0., 2., 2., false),
+            account.apply_with_delta(&dispute(0, 1)).unwrap()
+        );
+        assert_eq!(
+            delta(0., -2., -2., false),
+            account.apply_with_delta(&resolve(0, 1)).unwrap()
+        );
+        account.apply(&deposit(0, 2, 3.)).unwrap();
+        assert_eq!(
+            delta(-3., 3., 0., false),
+            account.apply_with_delta(&dispute(0, 2)).unwrap()
+        );
+        assert_eq!(
+            delta(0., -3., -3., true),
+            account.apply_with_delta(&chargeback(0, 2)).unwrap()
+        );
+        assert!(account.apply_with_delta(&deposit(0, 3, 1.)).is_err());
+    }
+
+    #[test]
+    fn dispute_of_withdrawal_with_funds_back_should_be_rejected_if_checked() {
+        let account = |check_withdrawal_disputes| {
+            let mut account = Account::with_policy(
+                0,
+                AccountPolicy {
+                    check_withdrawal_disputes,
+                    ..AccountPolicy::default()
+                },
+            );
+            account.apply(&deposit(0, 0, 5.)).unwrap();
+            account.apply(&withdrawal(0, 1, 2.)).unwrap();
+            account
+        };
+
+        let mut checked = account(true);
+        checked.apply(&dispute(0, 1)).unwrap();
+        assert_eq!(checked.total, Amount(5.));
+
+        let mut checked = account(true);
+        // Withdrawn funds returned outside of transaction handling
+        checked.credit(Amount(2.));
+        assert!(matches!(
+            checked.apply(&dispute(0, 1)),
+            Err(TransactionError::FundsRestored(1))
+        ));
+        assert_eq!(checked.held, Amount(0.));
+        assert_eq!(checked.total, Amount(5.));
+
+        let mut unchecked = account(false);
+        unchecked.credit(Amount(2.));
+        unchecked.apply(&dispute(0, 1)).unwrap();
+        assert_eq!(unchecked.total, Amount(7.));
+    }
+
+    #[cfg(feature = "fuzzing")]
+    #[test]
+    fn account_with_balances_should_follow_invariants() {
+        let mut account = Account::with_balances(1, Amount(3.), Amount(2.), None);
+        assert_eq!(account.total, Amount(5.));
+        account.check_invariants().unwrap();
+        assert!(matches!(
+            account.apply(&withdrawal(0, 1, 4.)),
+            Err(TransactionError::UnsufficientFunds(1))
+        ));
+
+        let mut locked =
+            Account::with_balances(1, Amount(3.), Amount(0.), Some(LockReason::Velocity));
+        assert!(locked.locked());
+        assert!(matches!(
+            locked.apply(&deposit(0, 1, 1.)),
+            Err(TransactionError::AccountLocked)
+        ));
+    }
+
+    #[test]
+    fn reused_id_should_not_change_balances() {
+        let mut account = Account::new(1);
+        account.apply(&deposit(0, 1, 5.)).unwrap();
+        account.apply(&withdrawal(0, 2, 1.)).unwrap();
+
+        assert!(matches!(
+            account.apply(&deposit(0, 1, 3.)),
+            Err(TransactionError::AlreadyExist(1))
+        ));
+        assert!(matches!(
+            account.apply(&withdrawal(0, 2, 2.)),
+            Err(TransactionError::AlreadyExist(2))
+        ));
+        assert_eq!(account.available, Amount(4.));
+        assert_eq!(account.total, Amount(4.));
+    }
+
+    #[test]
+    fn disputing_corrupted_history_entry_should_fail() {
+        let mut account = Account::new(1);
+        account.apply(&deposit(0, 1, 5.)).unwrap();
+        account.save_tx(&dispute(0, 2)).unwrap();
+
+        assert!(matches!(
+            account.apply(&dispute(0, 2)),
+            Err(TransactionError::CorruptHistory(2))
+        ));
+        assert_eq!(account.available, Amount(5.));
+        assert_eq!(account.held, Amount(0.));
+        assert!(!account.locked);
+    }
+
+    #[test]
+    fn locked_account_should_accept_deposits_if_allowed() {
+        let mut account = Account::with_policy(
+            1,
+            AccountPolicy {
+                locked_account: LockedAccountPolicy::AllowDeposits,
+                ..AccountPolicy::default()
+            },
+        );
+        account.apply(&deposit(0, 1, 5.)).unwrap();
+        account.apply(&deposit(0, 2, 3.)).unwrap();
+        account.apply(&dispute(0, 2)).unwrap();
+        account.apply(&chargeback(0, 2)).unwrap();
+        assert!(account.locked);
+
+        account.apply(&deposit(0, 3, 2.)).unwrap();
+        assert_eq!(account.available, Amount(7.));
+        assert_eq!(account.total, Amount(7.));
+        assert!(matches!(
+            account.apply(&withdrawal(0, 4, 1.)),
+            Err(TransactionError::AccountLocked)
+        ));
+        assert!(matches!(
+            account.apply(&dispute(0, 3)),
+            Err(TransactionError::AccountLocked)
+        ));
+        assert_eq!(account.available, Amount(7.));
+        assert!(account.locked);
+    }
+
+    #[test]
+    fn locked_account_should_block_everything_by_default() {
+        assert!([
+            TransactionType::Deposit,
+            TransactionType::Withdrawal,
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+        ]
+        .into_iter()
+        .all(|r#type| !LockedAccountPolicy::default().permits(r#type)));
+    }
+
+    #[test]
+    fn exactly_affordable_withdrawal_should_succeed_despite_float_errors() {
+        let mut account = Account::new(1);
+        account.apply(&deposit(0, 1, 0.7)).unwrap();
+        account.apply(&deposit(0, 2, 0.1)).unwrap();
+        // Sum of deposits is 0.7999999999999999 as float
+        assert!(account.available.0 < 0.8);
+
+        account.apply(&withdrawal(0, 3, 0.8)).unwrap();
+        assert_eq!(account.available, Amount(0.));
+        account.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn can_withdraw_should_agree_with_apply_at_the_boundary() {
+        let mut account = Account::new(1);
+        account.apply(&deposit(0, 1, 0.7)).unwrap();
+        account.apply(&deposit(0, 2, 0.1)).unwrap();
+
+        assert!(account.can_withdraw(Amount(0.8)));
+        assert!(!account.can_withdraw(Amount(0.8001)));
+        assert!(account.clone().apply(&withdrawal(0, 3, 0.8)).is_ok());
+        assert!(account.clone().apply(&withdrawal(0, 3, 0.8001)).is_err());
+
+        let mut overdraft = Account::with_policy(
+            1,
+            AccountPolicy {
+                allow_overdraft: true,
+                ..AccountPolicy::default()
+            },
+        );
+        assert!(overdraft.can_withdraw(Amount(0.8001)));
+        overdraft.lock(LockReason::Chargeback);
+        assert!(!overdraft.can_withdraw(Amount(0.)));
+    }
+
+    #[test]
+    fn can_withdraw_should_follow_velocity_limit() {
+        let mut account = Account::with_policy(
+            1,
+            AccountPolicy {
+                velocity_lock: Some(VelocityRule {
+                    max_withdrawals: 1,
+                    window: 2,
+                }),
+                ..AccountPolicy::default()
+            },
+        );
+        account.apply(&deposit(0, 1, 5.)).unwrap();
+        account.apply(&withdrawal(0, 2, 1.)).unwrap();
+
+        assert!(!account.can_withdraw(Amount(1.)));
+        assert!(account.clone().apply(&withdrawal(0, 3, 1.)).is_err());
+        account.apply(&deposit(0, 4, 1.)).unwrap();
+        assert!(account.can_withdraw(Amount(1.)));
+        assert!(account.apply(&withdrawal(0, 5, 1.)).is_ok());
+    }
+
+    #[test]
+    fn apply_should_tell_if_transaction_locked_account() {
+        let mut account = Account::with_policy(
+            0,
+            AccountPolicy {
+                locked_account: LockedAccountPolicy::AllowDeposits,
+                ..AccountPolicy::default()
+            },
+        );
+
+        let outcomes = [
+            account.apply(&deposit(0, 1, 5.)),
+            account.apply(&deposit(0, 2, 1.)),
+            account.apply(&withdrawal(0, 3, 1.)),
+            account.apply(&dispute(0, 1)),
+            account.apply(&resolve(0, 1)),
+            account.apply(&dispute(0, 1)),
+            account.apply(&chargeback(0, 1)),
+            account.apply(&deposit(0, 4, 1.)),
+        ]
+        .map(Result::unwrap);
+
+        assert_eq!(
+            [
+                TransactionOutcome::Applied,
+                TransactionOutcome::Applied,
+                TransactionOutcome::Applied,
+                TransactionOutcome::Applied,
+                TransactionOutcome::Applied,
+                TransactionOutcome::Applied,
+                TransactionOutcome::AppliedLockedAccount,
+                TransactionOutcome::Applied,
+            ],
+            outcomes
+        );
+        assert!(account.locked());
+        assert!(account.apply(&withdrawal(0, 5, 1.)).is_err());
+    }
+
+    #[test]
+    fn duplicate_ids_should_be_rejected_by_default() {
+        let mut account = Account::default();
+        account.apply(&deposit(0, 1, 5.)).unwrap();
+
+        assert!(matches!(
+            account.apply(&deposit(0, 1, 7.)),
+            Err(TransactionError::AlreadyExist(1))
+        ));
+        assert_eq!(account.available, Amount(5.));
+    }
+
+    #[test]
+    fn duplicate_ids_should_replace_undisputed_transactions_if_allowed() {
+        let policy = AccountPolicy {
+            on_duplicate: DuplicatePolicy::ReplaceIfNotDisputed,
+            ..AccountPolicy::default()
+        };
+        let mut account = Account::with_policy(0, policy);
+
+        account.apply(&deposit(0, 1, 5.)).unwrap();
+        account.apply(&withdrawal(0, 2, 1.)).unwrap();
+        account.apply(&deposit(0, 1, 7.)).unwrap();
+        account.apply(&withdrawal(0, 2, 2.)).unwrap();
+        assert_eq!(account.available, Amount(5.));
+        assert_eq!(account.total, Amount(5.));
+        assert_eq!(account.transaction_count(), 2);
+        assert_eq!(account.deposit_volume(), Amount(7.));
+        assert_eq!(account.withdrawal_volume(), Amount(2.));
+        assert!(matches!(
+            account.apply(&withdrawal(0, 1, 1.)),
+            Err(TransactionError::AlreadyExist(1))
+        ));
+
+        account.apply(&dispute(0, 2)).unwrap();
+        assert!(matches!(
+            account.apply(&withdrawal(0, 2, 3.)),
+            Err(TransactionError::AlreadyExist(2))
+        ));
+        assert_eq!(account.held, Amount(2.));
+
+        // Funds of the deposit were withdrawn, so a smaller version of it can't replace it
+        let mut spent = Account::with_policy(0, policy);
+        spent.apply(&deposit(0, 1, 5.)).unwrap();
+        spent.apply(&withdrawal(0, 2, 4.)).unwrap();
+        assert!(matches!(
+            spent.apply(&deposit(0, 1, 2.)),
+            Err(TransactionError::UnsufficientFunds(1))
+        ));
+        assert_eq!(spent.available, Amount(1.));
+        assert_eq!(spent.tx_history[&1].transaction.amount, Amount(5.));
+        spent.apply(&dispute(0, 1)).unwrap_err();
+        spent.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn failed_replacement_should_not_lock_account_nor_move_velocity_window() {
+        let mut account = Account::with_policy(
+            0,
+            AccountPolicy {
+                on_duplicate: DuplicatePolicy::ReplaceIfNotDisputed,
+                ..velocity_limited_account().policy
+            },
+        );
+        account.apply(&deposit(0, 0, 10.)).unwrap();
+        account.apply(&withdrawal(0, 1, 1.)).unwrap();
+        account.apply(&deposit(0, 2, 1.)).unwrap();
+        account.apply(&deposit(0, 3, 1.)).unwrap();
+        // Withdrawal 1 is out of the window by now, unlike these two
+        account.apply(&withdrawal(0, 4, 1.)).unwrap();
+        account.apply(&withdrawal(0, 5, 1.)).unwrap();
+        let (clock, recent_withdrawals) = (account.clock, account.recent_withdrawals.clone());
+
+        assert!(matches!(
+            account.apply(&withdrawal(0, 1, 3.)),
+            Err(TransactionError::VelocityExceeded(1))
+        ));
+        assert!(!account.locked());
+        assert_eq!(None, account.lock_reason());
+        assert_eq!(clock, account.clock);
+        assert_eq!(recent_withdrawals, account.recent_withdrawals);
+        assert_eq!(account.available, Amount(9.));
+        assert_eq!(account.tx_history[&1].transaction.amount, Amount(1.));
+        account.apply(&deposit(0, 6, 1.)).unwrap();
+    }
+
+    #[test]
+    fn replaced_withdrawal_should_not_count_twice_towards_velocity_limit() {
+        let mut account = Account::with_policy(
+            0,
+            AccountPolicy {
+                on_duplicate: DuplicatePolicy::ReplaceIfNotDisputed,
+                ..velocity_limited_account().policy
+            },
+        );
+        account.apply(&deposit(0, 0, 10.)).unwrap();
+        account.apply(&withdrawal(0, 1, 1.)).unwrap();
+        account.apply(&withdrawal(0, 2, 1.)).unwrap();
+
+        assert_eq!(
+            TransactionOutcome::Applied,
+            account.apply(&withdrawal(0, 2, 2.)).unwrap()
+        );
+        assert!(!account.locked());
+        assert_eq!(account.available, Amount(7.));
+        assert_eq!(VecDeque::from([2, 4]), account.recent_withdrawals);
+    }
+
+    #[test]
+    fn held_breakdown_should_list_disputed_transactions() {
+        let mut account = Account::new(1);
+        account.apply(&deposit(0, 1, 5.)).unwrap();
+        account.apply(&deposit(0, 2, 2.5)).unwrap();
+        account.apply(&deposit(0, 3, 1.)).unwrap();
+        account.apply(&withdrawal(0, 4, 0.5)).unwrap();
+        account.apply(&dispute(0, 3)).unwrap();
+        account.apply(&dispute(0, 1)).unwrap();
+        account.apply(&dispute(0, 4)).unwrap();
+        account.apply(&resolve(0, 4)).unwrap();
+
+        let breakdown = account.held_breakdown();
+        assert_eq!(vec![(1, Amount(5.)), (3, Amount(1.))], breakdown);
+        assert_eq!(
+            account.held,
+            breakdown.into_iter().map(|(_, amount)| amount).sum()
+        );
+    }
+
+    #[test]
+    fn underfunded_withdrawal_should_be_filled_partially_if_allowed() {
+        let mut account = Account::with_policy(
+            1,
+            AccountPolicy {
+                partial_withdrawal: true,
+                ..AccountPolicy::default()
+            },
+        );
+        account.apply(&deposit(0, 1, 3.)).unwrap();
+        account.apply(&withdrawal(0, 2, 5.)).unwrap();
+        assert_eq!(account.available, Amount(0.));
+        assert_eq!(account.total, Amount(0.));
+        assert_eq!(
+            vec![Amount(3.)],
+            account
+                .history()
+                .filter(|(id, _, _)| *id == 2)
+                .map(|(_, tx, _)| tx.amount)
+                .collect::<Vec<_>>()
+        );
+
+        // Nothing left to fill
+        assert!(matches!(
+            account.apply(&withdrawal(0, 3, 1.)),
+            Err(TransactionError::UnsufficientFunds(3))
+        ));
+        // Disputing holds the filled amount
+        account.apply(&dispute(0, 2)).unwrap();
+        assert_eq!(account.held, Amount(3.));
+        assert_eq!(account.total, Amount(3.));
+    }
+
+    #[test]
+    fn deposits_and_withdrawals_should_be_rounded_in_their_own_directions() {
+        let mut account = Account::with_policy(
+            0,
+            AccountPolicy {
+                rounding: DirectionalRounding {
+                    deposits: Some(RoundingMode::TowardZero),
+                    withdrawals: Some(RoundingMode::AwayFromZero),
+                },
+                ..AccountPolicy::default()
+            },
+        );
+
+        account.apply(&deposit(0, 1, 5.00009)).unwrap();
+        account.apply(&withdrawal(0, 2, 1.00001)).unwrap();
+
+        assert_eq!(account.available.0, 5. - 1.0001);
+        assert_eq!(account.tx_history[&1].transaction.amount.0, 5.);
+        assert_eq!(account.tx_history[&2].transaction.amount.0, 1.0001);
+
+        let mut uniform = Account::default();
+        uniform.apply(&deposit(0, 1, 5.00009)).unwrap();
+        uniform.apply(&withdrawal(0, 2, 1.00001)).unwrap();
+        assert_eq!(uniform.available.0, 5.00009 - 1.00001);
+    }
+
+    #[test]
+    fn deposit_crossing_max_total_should_be_rejected() {
+        let mut account = Account::with_policy(
+            0,
+            AccountPolicy {
+                max_total: Some(Amount(10.)),
+                ..AccountPolicy::default()
+            },
+        );
+        account.apply(&deposit(0, 1, 6.)).unwrap();
+        account.apply(&deposit(0, 2, 4.)).unwrap();
+
+        assert!(matches!(
+            account.apply(&deposit(0, 3, 0.0001)),
+            Err(TransactionError::LimitExceeded(3))
+        ));
+        assert_eq!(account.available, Amount(10.));
+        assert_eq!(account.total, Amount(10.));
+        assert!(!account.tx_history.contains_key(&3));
+
+        account.apply(&withdrawal(0, 4, 1.)).unwrap();
+        account.apply(&deposit(0, 5, 1.)).unwrap();
+        assert_eq!(account.total, Amount(10.));
+    }
+
+    #[test]
+    fn underfunded_withdrawal_should_be_rejected_by_default() {
+        let mut account = Account::new(1);
+        account.apply(&deposit(0, 1, 3.)).unwrap();
+        assert!(matches!(
+            account.apply(&withdrawal(0, 2, 5.)),
+            Err(TransactionError::UnsufficientFunds(2))
+        ));
+        assert_eq!(account.available, Amount(3.));
+        assert_eq!(account.is_disputed(2), None);
+    }
+
+    // Precision is a global setting without `std`, changing it would race with other tests
+    #[cfg(feature = "std")]
+    #[test]
+    fn disputes_should_move_amount_saved_regardless_of_precision_change() {
+        use crate::transaction::{set_precision, DECIMAL_PLACES};
+
+        let mut account = Account::default();
+        account
+            .apply(&Transaction {
+                amount: Amount::truncated(1.23456),
+                ..deposit(0, 1, 0.)
+            })
+            .unwrap();
+        account.apply(&deposit(0, 2, 1.)).unwrap();
+        let saved: Account =
+            serde_json::from_str(&serde_json::to_string(&account).unwrap()).unwrap();
+
+        set_precision(2);
+        // History restored at lower precision holds the exact amount, not the rounded one
+        let resaved: Account =
+            serde_json::from_str(&serde_json::to_string(&account).unwrap()).unwrap();
+        assert_eq!(resaved.tx_history[&1].transaction.amount.0, 1.2345);
+        for mut account in [account, saved] {
+            let (available, total) = (account.available.0, account.total.0);
+            account.apply(&dispute(0, 1)).unwrap();
+            assert_eq!(account.held.0, 1.2345);
+            assert_eq!(account.available.0, available - 1.2345);
+            assert_eq!(account.held_breakdown(), vec![(1, Amount(1.2345))]);
+
+            account.apply(&resolve(0, 1)).unwrap();
+            assert_eq!(account.held.0, 0.);
+            assert_eq!(account.available.0, available - 1.2345 + 1.2345);
+
+            account.apply(&dispute(0, 1)).unwrap();
+            account.apply(&chargeback(0, 1)).unwrap();
+            assert_eq!(account.held.0, 0.);
+            assert_eq!(account.total.0, total - 1.2345);
+        }
+        set_precision(DECIMAL_PLACES);
     }
 }
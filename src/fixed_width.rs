@@ -0,0 +1,157 @@
+use thiserror::Error;
+
+use crate::transaction::{Amount, Transaction, TransactionType};
+
+/// Widths in bytes of the consecutive columns of a fixed width record
+///
+/// Columns follow the order of csv input: type, client, tx and amount.
+/// The amount column may be cut short or missing for disputes, resolves and chargebacks.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FixedWidthLayout {
+    pub r#type: usize,
+    pub client: usize,
+    pub tx: usize,
+    pub amount: usize,
+}
+
+/// Possible errors of reading fixed width records
+#[derive(Error, Debug)]
+pub enum FixedWidthError {
+    #[error("Failed to read fixed width input: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid `{column}` column in line {line}: `{value}`")]
+    InvalidColumn {
+        line: usize,
+        column: &'static str,
+        value: String,
+    },
+}
+
+impl FixedWidthLayout {
+    /// Parse a single fixed width record, `line` is its 1-based number used in errors
+    pub fn parse(&self, record: &str, line: usize) -> Result<Transaction, FixedWidthError> {
+        let mut offset = 0;
+        let mut column = |name: &'static str, width: usize| {
+            let start = offset.min(record.len());
+            let end = (offset + width).min(record.len());
+            offset += width;
+            record
+                .get(start..end)
+                .map(str::trim)
+                .ok_or_else(|| FixedWidthError::InvalidColumn {
+                    line,
+                    column: name,
+                    value: record.to_string(),
+                })
+        };
+        let invalid = |column, value: &str| FixedWidthError::InvalidColumn {
+            line,
+            column,
+            value: value.to_string(),
+        };
+
+        let r#type = column("type", self.r#type)?;
+        let r#type = TransactionType::try_from(r#type).map_err(|_| invalid("type", r#type))?;
+        let client = column("client", self.client)?;
+        let client = client.parse().map_err(|_| invalid("client", client))?;
+        let tx = column("tx", self.tx)?;
+        let tx = tx.parse().map_err(|_| invalid("tx", tx))?;
+        let amount = match (column("amount", self.amount)?, r#type) {
+            (
+                "",
+                TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback,
+            ) => Amount(0.),
+            // Deposits and withdrawals need a finite, non-negative amount
+            (amount, TransactionType::Deposit | TransactionType::Withdrawal) => {
+                match amount.parse::<f64>() {
+                    Ok(val) if val.is_finite() && val >= 0. => Amount::truncated(val),
+                    _ => return Err(invalid("amount", amount)),
+                }
+            }
+            (amount, _) => {
+                Amount::truncated(amount.parse().map_err(|_| invalid("amount", amount))?)
+            }
+        };
+        Ok(Transaction {
+            r#type,
+            client,
+            tx,
+            amount,
+            idempotency_key: None,
+            timestamp: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAYOUT: FixedWidthLayout = FixedWidthLayout {
+        r#type: 10,
+        client: 5,
+        tx: 6,
+        amount: 10,
+    };
+
+    #[test]
+    fn fixed_width_records_should_be_parsed() {
+        let input = "deposit       1     1    5.12345\n\
+                     withdrawal    1     2  1.5\n\
+                     \n\
+                     dispute       1     1\n\
+                     chargeback    1     1          \n";
+
+        let txs: Vec<_> = input
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(i, line)| {
+                let tx = LAYOUT.parse(line, i + 1).unwrap();
+                (tx.r#type, tx.client, tx.tx, tx.amount)
+            })
+            .collect();
+
+        assert_eq!(
+            vec![
+                (TransactionType::Deposit, 1, 1, Amount(5.1234)),
+                (TransactionType::Withdrawal, 1, 2, Amount(1.5)),
+                (TransactionType::Dispute, 1, 1, Amount(0.)),
+                (TransactionType::Chargeback, 1, 1, Amount(0.)),
+            ],
+            txs
+        );
+    }
+
+    #[test]
+    fn negative_and_non_finite_amounts_should_be_reported() {
+        for record in [
+            "deposit       1     1    NaN",
+            "deposit       1     1    inf",
+            "withdrawal    1     2    -5",
+        ] {
+            assert!(
+                matches!(
+                    LAYOUT.parse(record, 1),
+                    Err(FixedWidthError::InvalidColumn {
+                        column: "amount",
+                        ..
+                    })
+                ),
+                "accepted `{record}`"
+            );
+        }
+    }
+
+    #[test]
+    fn missing_amount_of_deposit_should_be_reported() {
+        assert!(matches!(
+            LAYOUT.parse("deposit       1     2", 7),
+            Err(FixedWidthError::InvalidColumn {
+                line: 7,
+                column: "amount",
+                ..
+            })
+        ));
+    }
+}
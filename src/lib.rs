@@ -0,0 +1,68 @@
+//! A simple engine applying client transactions to their accounts
+//!
+//! Without the default `std` feature only the core of the engine is available,
+//! that is `account` and `transaction` modules, which need nothing but `alloc`.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+pub mod account;
+#[cfg(feature = "std")]
+pub mod fixed_width;
+mod math;
+#[cfg(feature = "std")]
+pub mod output;
+#[cfg(feature = "std")]
+pub mod record;
+#[cfg(feature = "std")]
+pub mod report;
+#[cfg(feature = "std")]
+pub mod service;
+#[cfg(feature = "std")]
+pub mod source;
+#[cfg(feature = "std")]
+pub mod store;
+#[cfg(any(feature = "testutil", test))]
+pub mod testutil;
+pub mod transaction;
+
+#[cfg(feature = "std")]
+pub use service::Service;
+
+/// Tests of the core built without `std`, with `cargo test --no-default-features`
+#[cfg(all(test, not(feature = "std")))]
+mod core_tests {
+    use crate::account::{Account, TransactionError};
+    use crate::testutil::{chargeback, deposit, dispute, withdrawal};
+    use crate::transaction::{Amount, RoundingMode};
+
+    #[test]
+    fn core_should_apply_transactions() {
+        let mut account = Account::new(1);
+
+        account.apply(&deposit(1, 1, 5.)).unwrap();
+        account.apply(&withdrawal(1, 2, 1.5)).unwrap();
+        account.apply(&dispute(1, 2)).unwrap();
+        account.apply(&chargeback(1, 2)).unwrap();
+
+        assert_eq!(Amount(5.), account.available());
+        assert!(account.locked());
+        assert!(matches!(
+            account.apply(&deposit(1, 3, 1.)),
+            Err(TransactionError::AccountLocked)
+        ));
+    }
+
+    #[test]
+    fn core_should_round_amounts() {
+        assert_eq!(
+            Amount(1.0002),
+            Amount(1.00015).rounded(RoundingMode::HalfEven)
+        );
+        assert_eq!(
+            Amount(1.0003),
+            Amount(1.00025).rounded(RoundingMode::HalfUp)
+        );
+    }
+}
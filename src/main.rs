@@ -1,93 +1,828 @@
-use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, Read, Write};
 
-pub(crate) mod account;
-pub(crate) mod transaction;
-
-use account::{Account, TransactionResult};
-use transaction::{ClientId, Transaction};
+use transactions_engine::output::AmountStyle;
+use transactions_engine::record::{self, RecordError};
+use transactions_engine::report::RejectedTransaction;
+use transactions_engine::transaction::{
+    Amount, Transaction, TransactionId, TransactionType, DECIMAL_PLACES,
+};
+use transactions_engine::Service;
 
 /// Parse commandline arguments and apply all transactions from given csv to accounts
 ///
-/// Output all the accounts as a csv on the process's stdout
+/// Output all the accounts as a csv on the process's stdout, or into the file given by `--output`
 /// Output all warnings regarding failed transactions on the process's stderr
+///
+/// Logging verbosity can be changed with `RUST_LOG`, warnings are logged by default
 fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"))
+        .format(|buf, record| {
+            writeln!(
+                buf,
+                "{} - {}",
+                record.level().as_str().to_lowercase(),
+                record.args()
+            )
+        })
+        .init();
+
     let args: Vec<String> = std::env::args().collect();
-    let input_file = match args.len() {
-        2 => &args[1],
-        _ => {
-            eprintln!("Usage: {} <path_to_csv_with_transactions>", args[0]);
-            std::process::exit(1);
-        }
+    let options = Options::parse(&args[1..]).unwrap_or_else(|| {
+        eprintln!(
+            "Usage: {} [--summary] [--gzip] [--empty-amount-means-all] [--errors-out <path>] [--strict-apply] [--max-errors <count>] [--sign-key <path> [--signature <path>]] [--precision <decimal_places>] [--warn-precision-loss] [--input-format csv|jsonl] [--format csv|bincode] [--quote-all] [--amount-style shortest|trim|fixed] [--only-active] [--extended] [--skip-bad-utf8] [--sort-input] [--check-ledger] [--output <path>] <path_to_csv_with_transactions>...",
+            args[0]
+        );
+        std::process::exit(1);
+    });
+    transactions_engine::transaction::set_precision(options.precision);
+    let mut builder = Service::builder().amount_style(options.amount_style);
+    if options.quote_all {
+        builder = builder.quote_all();
+    }
+    if options.only_active {
+        builder = builder.only_active();
+    }
+    if options.extended {
+        builder = builder.extended_output();
+    }
+    let mut service = builder.build();
+
+    // Output file is created up front, so that a wrong path is reported before processing
+    let mut output: Box<dyn Write> = match &options.output {
+        Some(path) => Box::new(
+            File::create(path)
+                .map(std::io::BufWriter::new)
+                .unwrap_or_else(|e| fatal(format!("Couldn't create file {path}: {e}"))),
+        ),
+        None => Box::new(std::io::stdout()),
     };
+    let mut errors_out = options.errors_out.as_ref().map(|path| {
+        File::create(path)
+            .map(std::io::BufWriter::new)
+            .unwrap_or_else(|e| fatal(format!("Couldn't create file {path}: {e}")))
+    });
+    // Sorted input is buffered whole, as the last record may need to be applied first
+    let mut buffered = Vec::new();
+    for input_file in &options.input_files {
+        let input = open_input(input_file, options.gzip)
+            .unwrap_or_else(|e| fatal(format!("Couldn't open file {input_file}: {e}")));
+        let (read, skipped) = if options.sort_input {
+            let mut skipped = 0;
+            let before = buffered.len();
+            buffered.extend(read_records(input, &options, &mut skipped));
+            (buffered.len() - before, skipped)
+        } else {
+            process(
+                &mut service,
+                input,
+                &options,
+                errors_out.as_mut().map(|out| out as &mut dyn Write),
+            )
+        };
+        // An input without transactions is valid, it just leaves the accounts as they are
+        log::info!("Read {read} transactions from {input_file}");
+        if skipped > 0 {
+            log::warn!("Skipped {skipped} records of {input_file} that aren't valid utf8");
+        }
+    }
+    if options.sort_input {
+        buffered.sort_by_key(sort_key);
+        apply_records(
+            &mut service,
+            buffered.into_iter(),
+            &options,
+            errors_out.as_mut().map(|out| out as &mut dyn Write),
+        );
+    }
+    if let Some(Err(e)) = errors_out.as_mut().map(Write::flush) {
+        fatal(format!("Failed to write rejected transactions: {e}"));
+    }
+    if options.check_ledger {
+        if let Err(e) = service.check_ledger() {
+            log::error!("{e}");
+        }
+    }
+
+    match &options.sign_key {
+        #[cfg(feature = "bincode")]
+        None if options.output_format == OutputFormat::Bincode => service
+            .write_bincode(&mut output)
+            .unwrap_or_else(|e| fatal(format!("Failed to print the state of accounts: {e}"))),
+        Some(key_path) => {
+            let signing_key = read_signing_key(key_path)
+                .unwrap_or_else(|e| fatal(format!("Couldn't read key {key_path}: {e}")));
+            let signature = service
+                .write_signed_csv(&mut output, &signing_key)
+                .unwrap_or_else(|e| fatal(format!("Failed to print the state of accounts: {e}")));
+            let signature_path = options
+                .signature_path()
+                .expect("signing requires a signature path");
+            std::fs::write(&signature_path, signature.to_bytes())
+                .unwrap_or_else(|e| fatal(format!("Couldn't write {signature_path}: {e}")));
+        }
+        None => service
+            .write_csv(&mut output)
+            .unwrap_or_else(|e| fatal(format!("Failed to print the state of accounts: {e}"))),
+    }
+    if let Err(e) = output.flush() {
+        fatal(format!("Failed to print the state of accounts: {e}"));
+    }
+
+    if options.summary {
+        eprint!("{}", service.stats());
+    }
+}
+
+/// Open the input file, decompressing it when `gzip` is set or the file has `.gz` extension
+fn open_input(path: &str, gzip: bool) -> std::io::Result<Box<dyn Read>> {
+    let file = File::open(path)?;
+    if gzip || path.ends_with(".gz") {
+        Ok(Box::new(flate2::read::GzDecoder::new(file)))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// Read an ed25519 signing key stored as 32 raw bytes of its secret
+fn read_signing_key(path: &str) -> std::io::Result<ed25519_dalek::SigningKey> {
+    let secret = std::fs::read(path)?;
+    let secret = secret.try_into().map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "expected 32 bytes of a secret key",
+        )
+    })?;
+    Ok(ed25519_dalek::SigningKey::from_bytes(&secret))
+}
+
+/// Apply all transactions read from `input` in the format of `options` to accounts of `service`
+///
+/// Rejected transactions are logged as warnings and, if `errors_out` is given,
+/// written there as json lines. With `strict_apply` the first rejected transaction
+/// ends the program instead. Returns the number of transactions read, applied or not,
+/// and the number of records skipped as invalid utf8 with `skip_bad_utf8`.
+fn process<R: Read>(
+    service: &mut Service,
+    input: R,
+    options: &Options,
+    errors_out: Option<&mut dyn Write>,
+) -> (usize, usize) {
+    let mut skipped = 0;
+    let read = apply_records(
+        service,
+        read_records(input, options, &mut skipped),
+        options,
+        errors_out,
+    );
+    (read, skipped)
+}
+
+/// Read transactions from `input` in the format of `options`, counting records skipped
+/// as invalid utf8 in `skipped`
+fn read_records<'a, R: Read + 'a>(
+    input: R,
+    options: &'a Options,
+    skipped: &'a mut usize,
+) -> Box<dyn Iterator<Item = Result<Transaction, RecordError>> + 'a> {
+    match options.input_format {
+        InputFormat::Csv => Box::new(csv_reader(input).into_records().filter_map(|record| {
+            let record = match record {
+                Ok(record) => record,
+                Err(e)
+                    if options.skip_bad_utf8 && matches!(e.kind(), csv::ErrorKind::Utf8 { .. }) =>
+                {
+                    log::warn!("Skipping record: {e}");
+                    *skipped += 1;
+                    return None;
+                }
+                Err(e) => fatal(format!("Failed to decode record as utf8: {e}")),
+            };
+            let amount = options
+                .warn_precision_loss
+                .then(|| record.get(3).and_then(|amount| amount.parse().ok()))
+                .flatten();
+            Some(Transaction::try_from(record).inspect(|tx| warn_on_precision_loss(tx, amount)))
+        })),
+        InputFormat::Jsonl => Box::new(
+            std::io::BufReader::new(input)
+                .lines()
+                .enumerate()
+                .filter_map(|(i, line)| {
+                    let mut line = match line {
+                        Ok(line) => line,
+                        // Lines are consumed even if they aren't valid utf8, so reading goes on
+                        Err(e)
+                            if options.skip_bad_utf8
+                                && e.kind() == std::io::ErrorKind::InvalidData =>
+                        {
+                            log::warn!("Skipping json line {}: {e}", i + 1);
+                            *skipped += 1;
+                            return None;
+                        }
+                        Err(e) => fatal(format!("Failed to read json line: {e}")),
+                    };
+                    // Byte order mark left by some editors isn't valid json, unlike in csv
+                    // where the reader strips it from the header itself
+                    if i == 0 && line.starts_with('\u{feff}') {
+                        line.drain(..'\u{feff}'.len_utf8());
+                    }
+                    Some((i as u64 + 1, line))
+                })
+                .filter(|(_, line)| !line.trim().is_empty())
+                .map(|(line, json)| {
+                    let amount = options
+                        .warn_precision_loss
+                        .then(|| json_amount(&json))
+                        .flatten();
+                    record::parse_json_line(&json, line)
+                        .inspect(|tx| warn_on_precision_loss(tx, amount))
+                }),
+        ),
+    }
+}
+
+/// Get the key input sorted with `--sort-input` is ordered by
+///
+/// Disputes, resolves and chargebacks carry the id of the transaction they reference,
+/// so they go after it, keeping their own order. Records that aren't valid transactions
+/// end the program once applied, so they go first for that to happen before anything
+/// is applied, apart from withdrawals with an empty amount handled by `apply_records`.
+fn sort_key(record: &Result<Transaction, RecordError>) -> Option<(TransactionId, bool)> {
+    match record {
+        Ok(tx) | Err(RecordError::EmptyAmount { tx, .. }) => Some((tx.tx, !tx.is_monetary())),
+        Err(_) => None,
+    }
+}
 
-    let mut service = Service::new();
+/// Log a warning if `amount` of `tx`, as written in input, got truncated on reading
+fn warn_on_precision_loss(tx: &Transaction, amount: Option<f64>) {
+    if let Some(warning) = precision_loss_warning(tx, amount) {
+        log::warn!("{warning}");
+    }
+}
 
+/// Describe truncation of `amount` of `tx` as written in input, if it dropped any digits
+fn precision_loss_warning(tx: &Transaction, amount: Option<f64>) -> Option<String> {
+    amount
+        .filter(|&amount| Amount::loses_precision(amount))
+        .map(|amount| {
+            format!(
+                "Amount `{amount}` of transaction `{}` truncated to `{}`",
+                tx.tx, tx.amount.0
+            )
+        })
+}
+
+/// Get amount of a json transaction the way it is written, before it is truncated
+fn json_amount(json: &str) -> Option<f64> {
+    serde_json::from_str::<serde_json::Value>(json)
+        .ok()?
+        .get("amount")?
+        .as_f64()
+}
+
+/// Apply transactions read from input, see `process`
+fn apply_records(
+    service: &mut Service,
+    records: impl Iterator<Item = Result<Transaction, RecordError>>,
+    options: &Options,
+    mut errors_out: Option<&mut dyn Write>,
+) -> usize {
+    let mut read = 0;
+    let mut rejected = 0;
+    for record in records {
+        let tx = match record {
+            Ok(tx) => tx,
+            Err(RecordError::EmptyAmount { mut tx, .. })
+                if options.empty_amount_means_all && tx.r#type == TransactionType::Withdrawal =>
+            {
+                let available = service.account(tx.client).map_or(0., |a| a.available().0);
+                tx.amount = Amount(available.max(0.));
+                tx
+            }
+            Err(e) => fatal(format!("Failed to read transaction: {e}")),
+        };
+        read += 1;
+        if let Err(e) = service.apply(&tx) {
+            rejected += 1;
+            let too_many = options.max_errors.is_some_and(|max| rejected >= max);
+            if !options.strict_apply {
+                log::warn!("{}: {e}", e.code());
+            }
+            if let Some(out) = errors_out.as_mut() {
+                serde_json::to_writer(&mut *out, &RejectedTransaction::new(&tx, &e))
+                    .map_err(std::io::Error::from)
+                    .and_then(|_| writeln!(out))
+                    .and_then(|_| {
+                        if options.strict_apply || too_many {
+                            out.flush()
+                        } else {
+                            Ok(())
+                        }
+                    })
+                    .unwrap_or_else(|e| {
+                        fatal(format!("Failed to write rejected transaction: {e}"))
+                    });
+            }
+            if options.strict_apply {
+                fatal(format!(
+                    "Transaction `{}` of client `{}` rejected, aborting: {e}",
+                    tx.tx, tx.client
+                ));
+            }
+            if too_many {
+                fatal(format!(
+                    "Rejected {rejected} transactions, reaching the limit of --max-errors, aborting"
+                ));
+            }
+        }
+    }
+    read
+}
+
+/// Create a csv reader of transactions from `input`
+fn csv_reader<R: Read>(input: R) -> csv::Reader<R> {
     csv::ReaderBuilder::new()
         .trim(csv::Trim::All)
         .flexible(true)
-        .from_path(input_file)
-        .expect(&format!("Couldn't open file {}", input_file))
-        .into_records()
-        .map(|res| res.expect("Failed to decode record as utf8"))
-        .map(deserialize_record)
-        .map(|res| res.expect("Failed to read transaction"))
-        .for_each(|tx| {
-            if let Err(e) = service.apply(tx) {
-                eprintln!("warn - {e}");
-            }
-        });
+        .from_reader(input)
+}
 
-    let mut csv_writer = csv::WriterBuilder::new().from_writer(std::io::stdout());
+/// Formats of input files
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum InputFormat {
+    #[default]
+    Csv,
+    /// Json object of a transaction per line
+    Jsonl,
+}
 
-    for account in service.accounts.values() {
-        csv_writer.serialize(account).expect(&format!(
-            "Failed to print the state for account with client id: {}",
-            account.id()
-        ));
-    }
+/// Formats of the state of accounts written on stdout or into `--output`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    #[default]
+    Csv,
+    /// Sequence of accounts encoded with bincode, see `output::write_bincode`
+    #[cfg(feature = "bincode")]
+    Bincode,
 }
 
-/// An exchanging service is a container for all created user accounts
-///
-/// It handles dispatching transactions to correct accounts as well as
-/// creating new accounts where needed
-pub struct Service {
-    accounts: HashMap<ClientId, Account>,
+/// Commandline options of the program
+#[derive(Debug, PartialEq)]
+struct Options {
+    /// Paths of input files, applied in order as a single stream of transactions
+    input_files: Vec<String>,
+    /// Print counters of applied and rejected transactions on stderr
+    summary: bool,
+    /// Decompress input file regardless of its extension
+    gzip: bool,
+    /// Path of a file to write rejected transactions to as json lines
+    errors_out: Option<String>,
+    /// Exit with an error on the first rejected transaction instead of carrying on
+    strict_apply: bool,
+    /// Exit with an error once this many transactions got rejected
+    max_errors: Option<usize>,
+    /// Warn about amounts with more decimal places than `precision`, which get truncated
+    warn_precision_loss: bool,
+    /// Treat withdrawals with an empty amount as withdrawals of all available funds
+    empty_amount_means_all: bool,
+    /// Path of an ed25519 key to sign the output with, requires `signature` or `output`
+    sign_key: Option<String>,
+    /// Path to write the signature of the output to, `output` with `.sig` appended by default
+    signature: Option<String>,
+    /// Number of decimal places amounts are read and written with
+    precision: i32,
+    input_format: InputFormat,
+    /// Format of the output, signing is only supported for csv
+    output_format: OutputFormat,
+    /// Quote all the fields of csv output
+    quote_all: bool,
+    amount_style: AmountStyle,
+    /// Skip unlocked accounts with all the balances at zero in the output
+    only_active: bool,
+    /// Add the number of transactions and gross volumes of each account to csv output
+    extended: bool,
+    /// Path of a file to write the state of accounts to instead of stdout
+    output: Option<String>,
+    /// Skip records that aren't valid utf8 with a warning instead of exiting
+    skip_bad_utf8: bool,
+    /// Apply transactions of all the input files in order of their ids, loading them
+    /// into memory first
+    sort_input: bool,
+    /// Log an error if totals of the accounts don't add up to the ledger after all the input
+    check_ledger: bool,
 }
 
-impl Service {
-    /// Create a new service
-    fn new() -> Self {
+impl Default for Options {
+    fn default() -> Self {
         Self {
-            accounts: HashMap::new(),
+            input_files: Vec::new(),
+            summary: false,
+            gzip: false,
+            errors_out: None,
+            strict_apply: false,
+            max_errors: None,
+            warn_precision_loss: false,
+            empty_amount_means_all: false,
+            sign_key: None,
+            signature: None,
+            precision: DECIMAL_PLACES,
+            input_format: InputFormat::default(),
+            output_format: OutputFormat::default(),
+            quote_all: false,
+            amount_style: AmountStyle::default(),
+            only_active: false,
+            extended: false,
+            output: None,
+            skip_bad_utf8: false,
+            sort_input: false,
+            check_ledger: false,
+        }
+    }
+}
+
+impl Options {
+    /// Parse commandline arguments, excluding the program name
+    ///
+    /// Returns `None` when arguments don't form a valid invocation
+    fn parse(args: &[String]) -> Option<Self> {
+        let mut options = Self::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--summary" => options.summary = true,
+                "--gzip" => options.gzip = true,
+                "--empty-amount-means-all" => options.empty_amount_means_all = true,
+                "--errors-out" => options.errors_out = Some(args.next()?.to_string()),
+                "--strict-apply" => options.strict_apply = true,
+                "--max-errors" => {
+                    options.max_errors = Some(args.next()?.parse().ok().filter(|&max| max > 0)?)
+                }
+                "--quote-all" => options.quote_all = true,
+                "--only-active" => options.only_active = true,
+                "--extended" => options.extended = true,
+                "--skip-bad-utf8" => options.skip_bad_utf8 = true,
+                "--sort-input" => options.sort_input = true,
+                "--check-ledger" => options.check_ledger = true,
+                "--warn-precision-loss" => options.warn_precision_loss = true,
+                "--sign-key" => options.sign_key = Some(args.next()?.to_string()),
+                "--signature" => options.signature = Some(args.next()?.to_string()),
+                "--output" => options.output = Some(args.next()?.to_string()),
+                "--input-format" => {
+                    options.input_format = match args.next()?.as_str() {
+                        "csv" => InputFormat::Csv,
+                        "jsonl" => InputFormat::Jsonl,
+                        _ => return None,
+                    }
+                }
+                "--format" => {
+                    options.output_format = match args.next()?.as_str() {
+                        "csv" => OutputFormat::Csv,
+                        #[cfg(feature = "bincode")]
+                        "bincode" => OutputFormat::Bincode,
+                        _ => return None,
+                    }
+                }
+                "--amount-style" => {
+                    options.amount_style = match args.next()?.as_str() {
+                        "shortest" => AmountStyle::Shortest,
+                        "trim" => AmountStyle::Trimmed,
+                        "fixed" => AmountStyle::Fixed,
+                        _ => return None,
+                    }
+                }
+                "--precision" => {
+                    options.precision =
+                        args.next()?.parse().ok().filter(|p| (0..=15).contains(p))?
+                }
+                flag if flag.starts_with("--") => return None,
+                path => options.input_files.push(path.to_string()),
+            }
+        }
+        if options.input_files.is_empty()
+            || (options.sign_key.is_some() && options.output_format != OutputFormat::Csv)
+            || (options.sign_key.is_some() && options.signature_path().is_none())
+        {
+            return None;
         }
+        Some(options)
     }
 
-    /// Dispatch a transaction to correct account and create one if it doesn't exist yet
-    pub fn apply(&mut self, tx: Transaction) -> TransactionResult<()> {
-        self.accounts
-            .entry(tx.client)
-            .or_insert(Account::new(tx.client))
-            .apply(tx)
+    /// Get path of the signature of the output, if it has one to go to
+    fn signature_path(&self) -> Option<String> {
+        self.signature
+            .clone()
+            .or_else(|| self.output.as_ref().map(|output| format!("{output}.sig")))
     }
 }
 
-/// Convert `csv::StringRecord` to a valid `Transaction`
-///
-/// In case that transaction is one of `dispute`, `resolve`, `chargeback`, the `amount`
-/// field can be missing in input as it is not meaningful in this context. In those cases
-/// to correctly deserialize a record, a placeholder `0.0` value is pushed in it's place
-/// so that `StringRecord::deserialize` will still work.
-fn deserialize_record(mut record: csv::StringRecord) -> Result<Transaction, csv::Error> {
-    let tx_type = record.get(0).expect("An empty record as an input");
-    match tx_type {
-        "dispute" | "resolve" | "chargeback" => {
-            if record.len() == 3 {
-                record.push_field("0.0");
-            }
+/// Log an error that prevents further processing and exit
+fn fatal(message: String) -> ! {
+    log::error!("{message}");
+    std::process::exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn options_should_be_parsed_regardless_of_order() {
+        let expected = Some(Options {
+            input_files: vec!["input.csv".to_string()],
+            summary: true,
+            errors_out: Some("errors.jsonl".to_string()),
+            ..Options::default()
+        });
+
+        assert_eq!(
+            expected,
+            Options::parse(&args(&[
+                "--summary",
+                "--errors-out",
+                "errors.jsonl",
+                "input.csv"
+            ]))
+        );
+        assert_eq!(
+            expected,
+            Options::parse(&args(&[
+                "input.csv",
+                "--errors-out",
+                "errors.jsonl",
+                "--summary"
+            ]))
+        );
+    }
+
+    #[test]
+    fn precision_should_be_parsed() {
+        assert_eq!(
+            8,
+            Options::parse(&args(&["--precision", "8", "a.csv"]))
+                .unwrap()
+                .precision
+        );
+        assert_eq!(
+            DECIMAL_PLACES,
+            Options::parse(&args(&["a.csv"])).unwrap().precision
+        );
+    }
+
+    #[test]
+    fn amount_style_should_be_parsed() {
+        assert_eq!(
+            AmountStyle::Fixed,
+            Options::parse(&args(&["--amount-style", "fixed", "a.csv"]))
+                .unwrap()
+                .amount_style
+        );
+        assert_eq!(
+            AmountStyle::Trimmed,
+            Options::parse(&args(&["--amount-style", "trim", "a.csv"]))
+                .unwrap()
+                .amount_style
+        );
+        assert_eq!(
+            AmountStyle::Shortest,
+            Options::parse(&args(&["a.csv"])).unwrap().amount_style
+        );
+    }
+
+    #[test]
+    fn input_files_should_be_kept_in_order() {
+        assert_eq!(
+            vec!["b.csv".to_string(), "a.csv".to_string()],
+            Options::parse(&args(&["b.csv", "--summary", "a.csv"]))
+                .unwrap()
+                .input_files
+        );
+    }
+
+    #[test]
+    fn invalid_options_should_be_rejected() {
+        assert_eq!(None, Options::parse(&args(&[])));
+        assert_eq!(None, Options::parse(&args(&["--unknown", "a.csv"])));
+        assert_eq!(None, Options::parse(&args(&["a.csv", "--errors-out"])));
+        assert_eq!(None, Options::parse(&args(&["a.csv", "--sign-key"])));
+        assert_eq!(None, Options::parse(&args(&["a.csv", "--output"])));
+        assert_eq!(None, Options::parse(&args(&["a.csv", "--precision", "-1"])));
+        assert_eq!(None, Options::parse(&args(&["a.csv", "--precision", "x"])));
+        assert_eq!(None, Options::parse(&args(&["a.csv", "--format", "xml"])));
+        assert_eq!(None, Options::parse(&args(&["a.csv", "--max-errors", "0"])));
+        assert_eq!(
+            None,
+            Options::parse(&args(&["a.csv", "--amount-style", "wide"]))
+        );
+    }
+
+    #[test]
+    fn signature_should_be_written_next_to_output_unless_given() {
+        assert_eq!(
+            Some("out.csv.sig".to_string()),
+            Options::parse(&args(&["--sign-key", "k", "--output", "out.csv", "a.csv"]))
+                .unwrap()
+                .signature_path()
+        );
+        assert_eq!(
+            Some("a.sig".to_string()),
+            Options::parse(&args(&[
+                "--sign-key",
+                "k",
+                "--output",
+                "out.csv",
+                "--signature",
+                "a.sig",
+                "a.csv"
+            ]))
+            .unwrap()
+            .signature_path()
+        );
+        assert_eq!(
+            Some("a.sig".to_string()),
+            Options::parse(&args(&["--sign-key", "k", "--signature", "a.sig", "a.csv"]))
+                .unwrap()
+                .signature_path()
+        );
+        assert_eq!(None, Options::parse(&args(&["--sign-key", "k", "a.csv"])));
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_output_should_not_be_signed() {
+        assert_eq!(
+            OutputFormat::Bincode,
+            Options::parse(&args(&["--format", "bincode", "a.csv"]))
+                .unwrap()
+                .output_format
+        );
+        assert_eq!(
+            None,
+            Options::parse(&args(&["--format", "bincode", "--sign-key", "k", "a.csv"]))
+        );
+    }
+
+    #[test]
+    fn gzipped_input_should_be_processed() {
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 5.0\n\
+                   withdrawal, 1, 2, 1.5\n\
+                   deposit, 2, 3, 2.0\n\
+                   dispute, 2, 3\n";
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(csv.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let mut service = Service::new();
+
+        process(
+            &mut service,
+            flate2::read::GzDecoder::new(&compressed[..]),
+            &Options::default(),
+            None,
+        );
+
+        let account = |id| service.accounts().find(|a| a.id() == id).unwrap();
+        assert_eq!(Amount(3.5), account(1).available());
+        assert_eq!(Amount(2.), account(2).held());
+        assert_eq!(Amount(0.), account(2).available());
+    }
+
+    #[test]
+    fn rejected_transactions_should_be_written_as_json_lines() {
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 5.0\n\
+                   withdrawal, 1, 2, 7.5\n\
+                   resolve, 1, 1\n\
+                   dispute, 2, 8\n";
+        let mut errors_out = Vec::new();
+
+        process(
+            &mut Service::new(),
+            csv.as_bytes(),
+            &Options::default(),
+            Some(&mut errors_out),
+        );
+
+        let rejected: Vec<RejectedTransaction> = String::from_utf8(errors_out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let rejected: Vec<_> = rejected
+            .iter()
+            .map(|r| (r.tx, r.client, r.r#type, r.error.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                (2, 1, TransactionType::Withdrawal, "UnsufficientFunds"),
+                (1, 1, TransactionType::Resolve, "NotDisputed"),
+                (8, 2, TransactionType::Dispute, "NotFound"),
+            ],
+            rejected
+        );
+    }
+
+    #[test]
+    fn empty_withdrawal_amount_should_withdraw_all_if_asked_to() {
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 5.0\n\
+                   withdrawal, 1, 2,\n\
+                   withdrawal, 2, 3,\n";
+        let options = Options {
+            empty_amount_means_all: true,
+            ..Options::default()
+        };
+        let mut service = Service::new();
+
+        process(&mut service, csv.as_bytes(), &options, None);
+
+        assert_eq!(Amount(0.), service.account(1).unwrap().total());
+        assert_eq!(Amount(0.), service.account(2).unwrap().total());
+    }
+
+    #[test]
+    fn later_input_file_should_dispute_deposit_from_earlier_one() {
+        let dir = std::env::temp_dir().join(format!("transactions-engine-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let first = dir.join("first.csv");
+        let second = dir.join("second.csv");
+        std::fs::write(&first, "type, client, tx, amount\ndeposit, 1, 1, 5.0\n").unwrap();
+        std::fs::write(&second, "type, client, tx, amount\ndispute, 1, 1\n").unwrap();
+        let mut service = Service::new();
+
+        for path in [&first, &second] {
+            let input = open_input(path.to_str().unwrap(), false).unwrap();
+            process(&mut service, input, &Options::default(), None);
         }
-        _ => (),
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(Amount(5.), service.account(1).unwrap().held());
+        assert_eq!(Amount(0.), service.account(1).unwrap().available());
+    }
+
+    #[test]
+    fn jsonl_input_should_give_same_accounts_as_csv() {
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 5.0\n\
+                   withdrawal, 1, 2, 1.5\n\
+                   deposit, 2, 3, 2.0\n\
+                   dispute, 2, 3\n\
+                   chargeback, 2, 3,\n";
+        let jsonl = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 5.0}
+                       {"type": "withdrawal", "client": 1, "tx": 2, "amount": 1.5}
+
+                       {"type": "deposit", "client": 2, "tx": 3, "amount": 2.0}
+                       {"type": "dispute", "client": 2, "tx": 3}
+                       {"type": "chargeback", "client": 2, "tx": 3, "amount": null}"#;
+        let output = |input: &str, input_format| {
+            let mut service = Service::new();
+            let options = Options {
+                input_format,
+                ..Options::default()
+            };
+            process(&mut service, input.as_bytes(), &options, None);
+            let mut accounts: Vec<_> = service
+                .accounts()
+                .map(|a| (a.id(), a.available(), a.held(), a.total(), a.locked()))
+                .collect();
+            accounts.sort_by_key(|&(id, ..)| id);
+            accounts
+        };
+
+        assert_eq!(
+            output(csv, InputFormat::Csv),
+            output(jsonl, InputFormat::Jsonl)
+        );
+        assert!(output(jsonl, InputFormat::Jsonl)[1].4);
+    }
+
+    #[test]
+    fn truncated_amount_should_be_warned_about() {
+        let tx = |amount| Transaction {
+            r#type: TransactionType::Deposit,
+            client: 1,
+            tx: 7,
+            amount: Amount(amount),
+            idempotency_key: None,
+            timestamp: None,
+        };
+
+        assert_eq!(
+            Some("Amount `1.12349` of transaction `7` truncated to `1.1234`".to_string()),
+            precision_loss_warning(&tx(1.1234), Some(1.12349))
+        );
+        assert_eq!(None, precision_loss_warning(&tx(1.1234), Some(1.1234)));
+        // Values on the grid aren't truncated, even if scaling them isn't exact
+        assert_eq!(None, precision_loss_warning(&tx(0.0003), Some(0.0003)));
+        assert_eq!(None, precision_loss_warning(&tx(0.), None));
+        assert_eq!(
+            Some(1.12349),
+            json_amount(r#"{"type":"deposit","client":1,"tx":7,"amount":1.12349}"#)
+        );
     }
-    let header = csv::StringRecord::from(vec!["type", "client", "tx", "amount"]);
-    record.deserialize(Some(&header))
 }
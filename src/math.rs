@@ -0,0 +1,41 @@
+//! Floating point functions of `std`, provided by `libm` when building without it
+
+#[cfg(feature = "std")]
+pub(crate) fn trunc(x: f64) -> f64 {
+    x.trunc()
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn floor(x: f64) -> f64 {
+    x.floor()
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn round(x: f64) -> f64 {
+    x.round()
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn powi(x: f64, n: i32) -> f64 {
+    x.powi(n)
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn trunc(x: f64) -> f64 {
+    libm::trunc(x)
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn floor(x: f64) -> f64 {
+    libm::floor(x)
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn round(x: f64) -> f64 {
+    libm::round(x)
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn powi(x: f64, n: i32) -> f64 {
+    libm::pow(x, n.into())
+}
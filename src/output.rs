@@ -0,0 +1,476 @@
+use crate::account::Account;
+use crate::transaction::{precision, Amount, ClientId, RoundingMode};
+
+/// Names of the columns describing an account in the output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnNames {
+    pub client: String,
+    pub available: String,
+    pub held: String,
+    pub total: String,
+    pub locked: String,
+    /// Names of the columns only written in extended output, see `OutputOptions::extended`
+    pub transaction_count: String,
+    pub deposit_volume: String,
+    pub withdrawal_volume: String,
+}
+
+impl Default for ColumnNames {
+    fn default() -> Self {
+        Self {
+            client: "id".to_string(),
+            available: "available".to_string(),
+            held: "held".to_string(),
+            total: "total".to_string(),
+            locked: "locked".to_string(),
+            transaction_count: "tx_count".to_string(),
+            deposit_volume: "deposit_volume".to_string(),
+            withdrawal_volume: "withdrawal_volume".to_string(),
+        }
+    }
+}
+
+/// Textual form of amounts in csv output
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum AmountStyle {
+    /// Shortest form that reads back as the same number, eg. `1.0` or `1.1234`
+    #[default]
+    Shortest,
+    /// No trailing zeros nor a trailing decimal point, eg. `1` or `1.5`
+    Trimmed,
+    /// Always as many decimal places as the current precision, eg. `1.5000`
+    Fixed,
+}
+
+impl AmountStyle {
+    /// Format `val`, already rounded to the current precision, following the style
+    fn format(self, val: f64) -> String {
+        let val = if val == 0. { 0. } else { val };
+        match self {
+            AmountStyle::Shortest => format!("{val:?}"),
+            AmountStyle::Trimmed => {
+                let fixed = AmountStyle::Fixed.format(val);
+                if fixed.contains('.') {
+                    fixed
+                        .trim_end_matches('0')
+                        .trim_end_matches('.')
+                        .to_string()
+                } else {
+                    fixed
+                }
+            }
+            AmountStyle::Fixed => format!("{val:.*}", precision().max(0) as usize),
+        }
+    }
+}
+
+/// Options deciding how the state of accounts is written out
+#[derive(Debug, Clone, Default)]
+pub struct OutputOptions {
+    pub rounding: RoundingMode,
+    pub column_names: ColumnNames,
+    /// Write negative amounts in parentheses, eg. `(1.2345)` instead of `-1.2345`
+    pub accounting_negatives: bool,
+    /// Quote all the fields of csv, not only ones that require it
+    pub quote_all: bool,
+    pub amount_style: AmountStyle,
+    /// Skip accounts that are unlocked and have all the balances at zero
+    pub only_active: bool,
+    /// Add columns with the number of applied transactions and gross volumes of deposits
+    /// and withdrawals to csv output
+    pub extended: bool,
+}
+
+impl OutputOptions {
+    /// Check if `account` should be written out
+    fn includes(&self, account: &Account) -> bool {
+        !self.only_active
+            || account.locked()
+            || [account.available(), account.held(), account.total()]
+                .into_iter()
+                .any(|amount| amount != Amount(0.))
+    }
+}
+
+/// State of a single account as written to the output
+#[derive(Debug, serde::Serialize)]
+struct AccountRecord {
+    id: ClientId,
+    available: OutputAmount,
+    held: OutputAmount,
+    total: OutputAmount,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transaction_count: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deposit_volume: Option<OutputAmount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    withdrawal_volume: Option<OutputAmount>,
+}
+
+/// An amount rounded for the output, written following the accounting convention if asked to
+#[derive(Debug)]
+struct OutputAmount {
+    amount: Amount,
+    accounting_negatives: bool,
+    style: AmountStyle,
+}
+
+impl OutputAmount {
+    fn new(amount: Amount, options: &OutputOptions) -> Self {
+        Self {
+            amount: amount.rounded(options.rounding),
+            accounting_negatives: options.accounting_negatives,
+            style: options.amount_style,
+        }
+    }
+}
+
+impl serde::Serialize for OutputAmount {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let val = self.amount.0;
+        match self.style {
+            _ if self.accounting_negatives && val < 0. => {
+                serializer.collect_str(&format_args!("({})", self.style.format(-val)))
+            }
+            AmountStyle::Shortest => self.amount.serialize(serializer),
+            style => serializer.collect_str(&style.format(val)),
+        }
+    }
+}
+
+impl AccountRecord {
+    fn new(account: &Account, options: &OutputOptions) -> Self {
+        Self {
+            id: account.id(),
+            available: OutputAmount::new(account.available(), options),
+            held: OutputAmount::new(account.held(), options),
+            total: OutputAmount::new(account.total(), options),
+            locked: account.locked(),
+            transaction_count: options.extended.then(|| account.transaction_count()),
+            deposit_volume: options
+                .extended
+                .then(|| OutputAmount::new(account.deposit_volume(), options)),
+            withdrawal_volume: options
+                .extended
+                .then(|| OutputAmount::new(account.withdrawal_volume(), options)),
+        }
+    }
+}
+
+/// Write state of `accounts` as csv into `writer`
+///
+/// Header is only written along with the first account, so no accounts give an empty output
+pub fn write_csv<'a, W: std::io::Write>(
+    accounts: impl Iterator<Item = &'a Account>,
+    options: &OutputOptions,
+    writer: W,
+) -> csv::Result<()> {
+    let quote_style = if options.quote_all {
+        csv::QuoteStyle::Always
+    } else {
+        csv::QuoteStyle::Necessary
+    };
+    let mut csv_writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .quote_style(quote_style)
+        .from_writer(writer);
+    let names = &options.column_names;
+    for (i, account) in accounts
+        .filter(|account| options.includes(account))
+        .enumerate()
+    {
+        if i == 0 {
+            let mut header = vec![
+                &names.client,
+                &names.available,
+                &names.held,
+                &names.total,
+                &names.locked,
+            ];
+            if options.extended {
+                header.extend([
+                    &names.transaction_count,
+                    &names.deposit_volume,
+                    &names.withdrawal_volume,
+                ]);
+            }
+            csv_writer.write_record(header)?;
+        }
+        csv_writer.serialize(AccountRecord::new(account, options))?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+/// State of a single account as written in binary output, see `write_bincode`
+///
+/// Amounts are rounded the same as in csv output, negatives are always written as such
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct AccountState {
+    pub id: ClientId,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub locked: bool,
+}
+
+#[cfg(feature = "bincode")]
+impl AccountState {
+    fn new(account: &Account, options: &OutputOptions) -> Self {
+        Self {
+            id: account.id(),
+            available: account.available().rounded(options.rounding),
+            held: account.held().rounded(options.rounding),
+            total: account.total().rounded(options.rounding),
+            locked: account.locked(),
+        }
+    }
+}
+
+/// Write state of `accounts` into `writer` as a bincode encoded sequence of `AccountState`
+///
+/// Output is encoded with the standard configuration of bincode, eg. can be decoded
+/// with `bincode::serde::decode_from_std_read::<Vec<AccountState>, _, _>`
+#[cfg(feature = "bincode")]
+pub fn write_bincode<'a, W: std::io::Write>(
+    accounts: impl Iterator<Item = &'a Account>,
+    options: &OutputOptions,
+    mut writer: W,
+) -> Result<(), bincode::error::EncodeError> {
+    let states: Vec<_> = accounts
+        .filter(|account| options.includes(account))
+        .map(|account| AccountState::new(account, options))
+        .collect();
+    bincode::serde::encode_into_std_write(states, &mut writer, bincode::config::standard())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{chargeback, deposit, dispute, withdrawal};
+
+    fn write(accounts: &[Account], options: &OutputOptions) -> String {
+        let mut output = Vec::new();
+        write_csv(accounts.iter(), options, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn header_should_use_configured_column_names() {
+        let mut account = Account::new(1);
+        account.credit(Amount(1.5));
+        let options = OutputOptions {
+            column_names: ColumnNames {
+                client: "account_id".to_string(),
+                ..ColumnNames::default()
+            },
+            ..OutputOptions::default()
+        };
+
+        assert_eq!(
+            "account_id,available,held,total,locked\n1,1.5,0.0,1.5,false\n",
+            write(&[account], &options)
+        );
+    }
+
+    #[test]
+    fn all_fields_should_be_quoted_if_asked_to() {
+        let account = || {
+            let mut account = Account::new(1);
+            account.credit(Amount(1.5));
+            account
+        };
+        let options = OutputOptions {
+            quote_all: true,
+            ..OutputOptions::default()
+        };
+
+        assert_eq!(
+            "\"id\",\"available\",\"held\",\"total\",\"locked\"\n\
+             \"1\",\"1.5\",\"0.0\",\"1.5\",\"false\"\n",
+            write(&[account()], &options)
+        );
+        assert_eq!(
+            "id,available,held,total,locked\n1,1.5,0.0,1.5,false\n",
+            write(&[account()], &OutputOptions::default())
+        );
+    }
+
+    #[test]
+    fn no_accounts_should_give_empty_output() {
+        assert_eq!("", write(&[], &OutputOptions::default()));
+    }
+
+    #[test]
+    fn negative_amounts_should_be_written_in_parentheses_if_asked_to() {
+        let account = || {
+            let mut account = Account::new(1);
+            account.credit(Amount(-1.2345));
+            account
+        };
+        let options = OutputOptions {
+            accounting_negatives: true,
+            ..OutputOptions::default()
+        };
+
+        assert_eq!(
+            "id,available,held,total,locked\n1,(1.2345),0.0,(1.2345),false\n",
+            write(&[account()], &options)
+        );
+        assert_eq!(
+            "id,available,held,total,locked\n1,-1.2345,0.0,-1.2345,false\n",
+            write(&[account()], &OutputOptions::default())
+        );
+    }
+
+    #[test]
+    fn inactive_accounts_should_be_skipped_if_asked_to() {
+        let mut funded = Account::new(1);
+        funded.credit(Amount(1.5));
+        let mut drained = Account::new(2);
+        drained.credit(Amount(1.5));
+        drained.credit(Amount(-1.5));
+        let mut locked = Account::new(3);
+        locked.apply(&deposit(3, 1, 2.)).unwrap();
+        locked.apply(&dispute(3, 1)).unwrap();
+        locked.apply(&chargeback(3, 1)).unwrap();
+        let accounts = [funded, drained, Account::new(4), locked];
+        let options = OutputOptions {
+            only_active: true,
+            ..OutputOptions::default()
+        };
+
+        assert_eq!(
+            "id,available,held,total,locked\n1,1.5,0.0,1.5,false\n3,0.0,0.0,0.0,true\n",
+            write(&accounts, &options)
+        );
+        assert_eq!(
+            5,
+            write(&accounts, &OutputOptions::default()).lines().count()
+        );
+        assert_eq!("", write(&accounts[1..3], &options));
+    }
+
+    #[test]
+    fn amounts_should_follow_configured_style() {
+        let accounts: Vec<_> = [1.5, 2., 0.1234, -3.25]
+            .into_iter()
+            .enumerate()
+            .map(|(i, amount)| {
+                let mut account = Account::new(i as ClientId + 1);
+                account.credit(Amount(amount));
+                account
+            })
+            .collect();
+        let with_style = |amount_style| OutputOptions {
+            amount_style,
+            ..OutputOptions::default()
+        };
+
+        assert_eq!(
+            "id,available,held,total,locked\n\
+             1,1.5000,0.0000,1.5000,false\n\
+             2,2.0000,0.0000,2.0000,false\n\
+             3,0.1234,0.0000,0.1234,false\n\
+             4,-3.2500,0.0000,-3.2500,false\n",
+            write(&accounts, &with_style(AmountStyle::Fixed))
+        );
+        assert_eq!(
+            "id,available,held,total,locked\n\
+             1,1.5,0,1.5,false\n\
+             2,2,0,2,false\n\
+             3,0.1234,0,0.1234,false\n\
+             4,-3.25,0,-3.25,false\n",
+            write(&accounts, &with_style(AmountStyle::Trimmed))
+        );
+        assert_eq!(
+            "id,available,held,total,locked\n4,(3.2500),0.0000,(3.2500),false\n",
+            write(
+                &accounts[3..],
+                &OutputOptions {
+                    accounting_negatives: true,
+                    ..with_style(AmountStyle::Fixed)
+                }
+            )
+        );
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_output_should_decode_to_account_states() {
+        let mut first = Account::new(1);
+        first.credit(Amount(1.23456));
+        let mut second = Account::new(2);
+        second.credit(Amount(-2.5));
+        let mut output = Vec::new();
+        write_bincode(
+            [first, second].iter(),
+            &OutputOptions::default(),
+            &mut output,
+        )
+        .unwrap();
+
+        let states: Vec<AccountState> =
+            bincode::serde::decode_from_std_read(&mut &output[..], bincode::config::standard())
+                .unwrap();
+        assert_eq!(
+            vec![
+                AccountState {
+                    id: 1,
+                    available: Amount(1.2346),
+                    held: Amount(0.),
+                    total: Amount(1.2346),
+                    locked: false,
+                },
+                AccountState {
+                    id: 2,
+                    available: Amount(-2.5),
+                    held: Amount(0.),
+                    total: Amount(-2.5),
+                    locked: false,
+                },
+            ],
+            states
+        );
+        assert!(
+            output.len()
+                < write(
+                    &[Account::new(1), Account::new(2)],
+                    &OutputOptions::default()
+                )
+                .len()
+        );
+    }
+
+    #[test]
+    fn extended_output_should_add_activity_of_accounts() {
+        let mut account = Account::new(1);
+        for tx in [
+            deposit(1, 1, 5.),
+            deposit(1, 2, 2.5),
+            withdrawal(1, 3, 1.),
+            withdrawal(1, 4, 100.),
+            dispute(1, 2),
+            chargeback(1, 2),
+        ] {
+            let _ = account.apply(&tx);
+        }
+        let options = OutputOptions {
+            extended: true,
+            ..OutputOptions::default()
+        };
+
+        assert_eq!(
+            "id,available,held,total,locked,tx_count,deposit_volume,withdrawal_volume\n\
+             1,4.0,0.0,4.0,true,5,7.5,1.0\n",
+            write(&[account.clone()], &options)
+        );
+        assert_eq!(
+            "id,available,held,total,locked\n1,4.0,0.0,4.0,true\n",
+            write(&[account], &OutputOptions::default())
+        );
+    }
+}
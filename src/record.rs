@@ -0,0 +1,609 @@
+use thiserror::Error;
+
+use crate::transaction::{Amount, ClientId, Transaction, TransactionId, TransactionType};
+
+/// Possible errors of converting a csv record, or a json line, into a transaction
+#[derive(Error, Debug)]
+pub enum RecordError {
+    #[error(
+        "Empty record{}",
+        line.map(|line| format!(" in line {line}")).unwrap_or_default()
+    )]
+    Empty { line: Option<u64> },
+    #[error(
+        "Unknown transaction type `{type}`{}",
+        line.map(|line| format!(" in line {line}")).unwrap_or_default()
+    )]
+    UnknownType { line: Option<u64>, r#type: String },
+    #[error(
+        "Malformed record{}: found {found} fields, expected `{expected}`",
+        line.map(|line| format!(" in line {line}")).unwrap_or_default()
+    )]
+    Malformed {
+        line: Option<u64>,
+        found: usize,
+        expected: &'static str,
+    },
+    #[error(
+        "Empty amount of {}{}",
+        tx.r#type.as_str(),
+        line.map(|line| format!(" in line {line}")).unwrap_or_default()
+    )]
+    EmptyAmount {
+        line: Option<u64>,
+        /// The transaction read with an amount of 0
+        tx: Transaction,
+    },
+    #[error(
+        "Invalid amount `{amount}`{}",
+        line.map(|line| format!(" in line {line}")).unwrap_or_default()
+    )]
+    BadAmount { line: Option<u64>, amount: String },
+    #[error(
+        "Client id `{value}`{} out of range, expected at most {}",
+        line.map(|line| format!(" in line {line}")).unwrap_or_default(),
+        ClientId::MAX
+    )]
+    ClientIdOutOfRange { line: Option<u64>, value: String },
+    #[error(
+        "Transaction id `{value}`{} out of range, expected at most {}",
+        line.map(|line| format!(" in line {line}")).unwrap_or_default(),
+        TransactionId::MAX
+    )]
+    TxIdOutOfRange { line: Option<u64>, value: String },
+    #[error(
+        "Invalid {}{}: {reason}",
+        column.map(|column| format!("`{column}` column")).unwrap_or_else(|| "record".to_string()),
+        line.map(|line| format!(" in line {line}")).unwrap_or_default()
+    )]
+    Invalid {
+        line: Option<u64>,
+        /// Offset of the record in input, in bytes
+        byte: Option<u64>,
+        column: Option<&'static str>,
+        reason: String,
+    },
+    #[error("Invalid json in line {line}: {source}")]
+    Json {
+        line: u64,
+        source: serde_json::Error,
+    },
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+    #[cfg(feature = "async")]
+    #[error(transparent)]
+    CsvAsync(#[from] csv_async::Error),
+}
+
+/// Describe failure of deserializing a record with its position in input and failed column
+fn invalid_record(error: csv::Error) -> RecordError {
+    const COLUMNS: [&str; 6] = [
+        "type",
+        "client",
+        "tx",
+        "amount",
+        "idempotency_key",
+        "timestamp",
+    ];
+    match error.kind() {
+        csv::ErrorKind::Deserialize { pos, err } => RecordError::Invalid {
+            line: pos.as_ref().map(csv::Position::line),
+            byte: pos.as_ref().map(csv::Position::byte),
+            column: err
+                .field()
+                .and_then(|field| COLUMNS.get(field as usize).copied()),
+            reason: err.kind().to_string(),
+        },
+        _ => RecordError::Csv(error),
+    }
+}
+
+/// Check if `id` is a number too large to fit in id type `T`
+fn id_out_of_range<T: std::str::FromStr<Err = std::num::ParseIntError>>(id: &str) -> bool {
+    matches!(id.parse::<T>(), Err(e) if *e.kind() == std::num::IntErrorKind::PosOverflow)
+}
+
+/// Names of the columns of csv input, the last ones may be missing
+fn header() -> csv::StringRecord {
+    csv::StringRecord::from(vec![
+        "type",
+        "client",
+        "tx",
+        "amount",
+        "idempotency_key",
+        "timestamp",
+    ])
+}
+
+/// Replace field at `index` of `record` with `value`, keeping position of the record
+fn replace_field(record: &mut csv::StringRecord, index: usize, value: &str) {
+    let position = record.position().cloned();
+    *record = record
+        .iter()
+        .enumerate()
+        .map(|(i, field)| if i == index { value } else { field })
+        .collect();
+    record.set_position(position);
+}
+
+/// Convert `csv::StringRecord` to a valid `Transaction`
+///
+/// In case that transaction is one of `dispute`, `resolve`, `chargeback`, the `amount`
+/// field can be missing or empty in input as it is not meaningful in this context.
+/// In those cases to correctly deserialize a record, a placeholder `0.0` value is pushed
+/// in it's place so that `StringRecord::deserialize` will still work.
+///
+/// Transaction types are case-insensitive, eg. `Deposit` and `DEPOSIT` are both deposits.
+/// Records with all the fields empty, of unknown type or with an amount that isn't a number
+/// are reported with dedicated errors, as are deposits and withdrawals of a negative
+/// or non-finite amount. Records of any other shape are reported as malformed.
+/// Empty amount of deposits and withdrawals is reported separately, as its meaning
+/// for withdrawals is configurable. Optional fifth and sixth fields hold the idempotency key
+/// and the timestamp, empty ones mean no key or timestamp.
+impl TryFrom<csv::StringRecord> for Transaction {
+    type Error = RecordError;
+
+    fn try_from(mut record: csv::StringRecord) -> Result<Self, Self::Error> {
+        let line = record.position().map(csv::Position::line);
+        let malformed = |record: &csv::StringRecord, expected| RecordError::Malformed {
+            line,
+            found: record.len(),
+            expected,
+        };
+        if record.iter().all(str::is_empty) {
+            return Err(RecordError::Empty { line });
+        }
+        let tx_type = match record.get(0) {
+            Some(tx_type) if !tx_type.is_empty() => tx_type.to_lowercase(),
+            _ => return Err(malformed(&record, "type, client, tx, amount")),
+        };
+        if tx_type != record[0] {
+            replace_field(&mut record, 0, &tx_type);
+        }
+        match record.get(1) {
+            Some(client) if id_out_of_range::<ClientId>(client) => {
+                return Err(RecordError::ClientIdOutOfRange {
+                    line,
+                    value: client.to_string(),
+                })
+            }
+            _ => (),
+        }
+        match record.get(2) {
+            Some(tx) if id_out_of_range::<TransactionId>(tx) => {
+                return Err(RecordError::TxIdOutOfRange {
+                    line,
+                    value: tx.to_string(),
+                })
+            }
+            _ => (),
+        }
+        match TransactionType::try_from(tx_type.as_str()) {
+            Err(_) => {
+                return Err(RecordError::UnknownType {
+                    line,
+                    r#type: record[0].to_string(),
+                })
+            }
+            Ok(
+                TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback,
+            ) => match record.len() {
+                3 => record.push_field("0.0"),
+                4..=6 if record[3].is_empty() => replace_field(&mut record, 3, "0.0"),
+                4..=6 => (),
+                _ => {
+                    return Err(malformed(
+                        &record,
+                        "type, client, tx[, amount[, idempotency_key[, timestamp]]]",
+                    ))
+                }
+            },
+            _ if !(4..=6).contains(&record.len()) => {
+                return Err(malformed(
+                    &record,
+                    "type, client, tx, amount[, idempotency_key[, timestamp]]",
+                ))
+            }
+            Ok(_) if record[3].is_empty() => {
+                replace_field(&mut record, 3, "0.0");
+                return Err(RecordError::EmptyAmount {
+                    line,
+                    tx: record
+                        .deserialize(Some(&header()))
+                        .map_err(invalid_record)?,
+                });
+            }
+            _ => (),
+        }
+        // Amounts of disputes, resolves and chargebacks are placeholders that are never used,
+        // while deposits and withdrawals need a finite, non-negative amount
+        let monetary = matches!(
+            TransactionType::try_from(tx_type.as_str()),
+            Ok(TransactionType::Deposit | TransactionType::Withdrawal)
+        );
+        let valid_amount = match record[3].parse::<f64>() {
+            Ok(amount) => !monetary || (amount.is_finite() && amount >= 0.),
+            Err(_) => false,
+        };
+        if !valid_amount {
+            return Err(RecordError::BadAmount {
+                line,
+                amount: record[3].to_string(),
+            });
+        }
+        record.deserialize(Some(&header())).map_err(invalid_record)
+    }
+}
+
+/// Transaction as read from a json line, where amount is optional
+#[derive(serde::Deserialize)]
+struct JsonRecord {
+    r#type: TransactionType,
+    client: ClientId,
+    tx: TransactionId,
+    amount: Option<Amount>,
+    idempotency_key: Option<String>,
+    timestamp: Option<u64>,
+}
+
+/// Convert a json object in `line` of input to a valid `Transaction`
+///
+/// Amount can be omitted for `dispute`, `resolve` and `chargeback`. Deposits and withdrawals
+/// without an amount are reported the same as ones with an empty amount in csv, as are ones
+/// with a negative amount.
+pub fn parse_json_line(json: &str, line: u64) -> Result<Transaction, RecordError> {
+    let record: JsonRecord =
+        serde_json::from_str(json).map_err(|source| RecordError::Json { line, source })?;
+    let tx = Transaction {
+        r#type: record.r#type,
+        client: record.client,
+        tx: record.tx,
+        amount: record.amount.unwrap_or(Amount(0.)),
+        idempotency_key: record.idempotency_key,
+        timestamp: record.timestamp,
+    };
+    match (record.amount, record.r#type) {
+        (None, TransactionType::Deposit | TransactionType::Withdrawal) => {
+            Err(RecordError::EmptyAmount {
+                line: Some(line),
+                tx,
+            })
+        }
+        (Some(amount), TransactionType::Deposit | TransactionType::Withdrawal)
+            if amount < Amount(0.) =>
+        {
+            Err(RecordError::BadAmount {
+                line: Some(line),
+                amount: amount.0.to_string(),
+            })
+        }
+        _ => Ok(tx),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deserialize all records of `csv`, keeping their positions
+    fn deserialize_all(csv: &str) -> Vec<Result<Transaction, RecordError>> {
+        csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .from_reader(csv.as_bytes())
+            .into_records()
+            .map(|record| Transaction::try_from(record.unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn records_of_valid_shape_should_be_deserialized() {
+        let results = deserialize_all(
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 5.0\n\
+             dispute, 1, 1\n\
+             resolve, 1, 1,\n",
+        );
+
+        assert!(results.iter().all(Result::is_ok));
+    }
+
+    #[test]
+    fn records_of_invalid_shape_should_be_reported_with_line() {
+        let results = deserialize_all(
+            "type, client, tx, amount\n\
+             , 1\n\
+             dispute, 1\n\
+             deposit, 1, 2\n\
+             withdrawal, 1, 3, 1.0, k, 7, 8\n\
+             chargeback, 1, 3, 0.0, k, 7, 8\n",
+        );
+
+        let errors: Vec<_> = results
+            .into_iter()
+            .map(|result| match result {
+                Err(RecordError::Malformed { line, found, .. }) => (line.unwrap(), found),
+                other => panic!("Unexpected result {other:?}"),
+            })
+            .collect();
+        assert_eq!(vec![(2, 2), (3, 2), (4, 3), (5, 7), (6, 7)], errors);
+    }
+
+    #[test]
+    fn malformed_record_error_should_name_the_line() {
+        let error = deserialize_all("type, client, tx, amount\ndeposit, 1, 2\n")
+            .pop()
+            .unwrap()
+            .unwrap_err();
+
+        assert_eq!(
+            "Malformed record in line 2: found 3 fields, expected \
+             `type, client, tx, amount[, idempotency_key[, timestamp]]`",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn empty_amount_should_be_reported() {
+        let results = deserialize_all(
+            "type, client, tx, amount\n\
+             deposit, 1, 1,\n\
+             withdrawal, 1, 2,\n",
+        );
+
+        let errors: Vec<_> = results
+            .into_iter()
+            .map(|result| match result {
+                Err(RecordError::EmptyAmount { line, tx }) => (line.unwrap(), tx.r#type, tx.tx),
+                other => panic!("Unexpected result {other:?}"),
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (2, TransactionType::Deposit, 1),
+                (3, TransactionType::Withdrawal, 2)
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn transaction_types_should_be_case_insensitive() {
+        let results = deserialize_all(
+            "type, client, tx, amount\n\
+             Deposit, 1, 1, 5.0\n\
+             WITHDRAWAL, 1, 2, 1.5\n\
+             Dispute, 1, 1\n",
+        );
+
+        let txs: Vec<_> = results
+            .into_iter()
+            .map(|result| {
+                let tx = result.unwrap();
+                (tx.r#type, tx.client, tx.tx, tx.amount)
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (TransactionType::Deposit, 1, 1, Amount(5.)),
+                (TransactionType::Withdrawal, 1, 2, Amount(1.5)),
+                (TransactionType::Dispute, 1, 1, Amount(0.)),
+            ],
+            txs
+        );
+    }
+
+    #[test]
+    fn invalid_records_should_be_reported_with_dedicated_errors() {
+        let mut results = deserialize_all(
+            "type, client, tx, amount\n\
+             , , ,\n\
+             transfer, 1, 2, 1.0\n\
+             deposit, 1, 3, abc\n\
+             deposit, x, 4, 1.0\n",
+        )
+        .into_iter();
+
+        assert!(matches!(
+            results.next(),
+            Some(Err(RecordError::Empty { line: Some(2) }))
+        ));
+        assert!(matches!(
+            results.next(),
+            Some(Err(RecordError::UnknownType { line: Some(3), r#type })) if r#type == "transfer"
+        ));
+        assert!(matches!(
+            results.next(),
+            Some(Err(RecordError::BadAmount { line: Some(4), amount })) if amount == "abc"
+        ));
+        assert!(matches!(
+            results.next(),
+            Some(Err(RecordError::Invalid {
+                line: Some(5),
+                column: Some("client"),
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn negative_and_non_finite_amounts_should_be_reported() {
+        let results = deserialize_all(
+            "type, client, tx, amount\n\
+             deposit, 1, 1, NaN\n\
+             deposit, 1, 2, inf\n\
+             withdrawal, 1, 3, -5\n\
+             deposit, 1, 4, -inf\n",
+        );
+
+        let amounts: Vec<_> = results
+            .into_iter()
+            .map(|result| match result {
+                Err(RecordError::BadAmount { amount, .. }) => amount,
+                other => panic!("Expected bad amount, got {other:?}"),
+            })
+            .collect();
+        assert_eq!(vec!["NaN", "inf", "-5", "-inf"], amounts);
+    }
+
+    #[test]
+    fn ids_out_of_range_should_be_reported_with_value_and_line() {
+        let mut results = deserialize_all(
+            "type, client, tx, amount\n\
+             deposit, 70000, 1, 1.0\n\
+             dispute, 1, 4294967296\n\
+             deposit, 65535, 4294967295, 1.0\n",
+        )
+        .into_iter();
+
+        let error = results.next().unwrap().unwrap_err();
+        assert!(matches!(
+            &error,
+            RecordError::ClientIdOutOfRange { line: Some(2), value } if value == "70000"
+        ));
+        assert_eq!(
+            "Client id `70000` in line 2 out of range, expected at most 65535",
+            error.to_string()
+        );
+        let error = results.next().unwrap().unwrap_err();
+        assert!(matches!(
+            &error,
+            RecordError::TxIdOutOfRange { line: Some(3), value } if value == "4294967296"
+        ));
+        assert_eq!(
+            "Transaction id `4294967296` in line 3 out of range, expected at most 4294967295",
+            error.to_string()
+        );
+        assert!(results.next().unwrap().is_ok());
+    }
+
+    #[test]
+    fn invalid_record_should_be_reported_with_its_position() {
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 5.0\n\
+                   deposit, 1, -2, 1.0\n\
+                   deposit, 1, 3, 2.0\n";
+        let results = deserialize_all(csv);
+
+        assert!(results[0].is_ok() && results[2].is_ok());
+        let error = results.into_iter().nth(1).unwrap().unwrap_err();
+        assert!(matches!(
+            error,
+            RecordError::Invalid {
+                line: Some(3),
+                byte: Some(44),
+                column: Some("tx"),
+                ..
+            }
+        ));
+        assert_eq!(
+            "Invalid `tx` column in line 3: invalid digit found in string",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn records_of_each_type_should_convert_with_try_into() {
+        let convert = |fields: &[&str]| -> Result<Transaction, RecordError> {
+            csv::StringRecord::from(fields.to_vec()).try_into()
+        };
+
+        for (r#type, expected) in [
+            ("deposit", TransactionType::Deposit),
+            ("withdrawal", TransactionType::Withdrawal),
+        ] {
+            let tx = convert(&[r#type, "1", "2", "1.5"]).unwrap();
+            assert_eq!(
+                (expected, 1, 2, Amount(1.5)),
+                (tx.r#type, tx.client, tx.tx, tx.amount)
+            );
+            assert!(matches!(
+                convert(&[r#type, "1", "2", ""]),
+                Err(RecordError::EmptyAmount { tx, .. }) if tx.r#type == expected
+            ));
+            assert!(matches!(
+                convert(&[r#type, "1", "2"]),
+                Err(RecordError::Malformed { found: 3, .. })
+            ));
+        }
+        for (r#type, expected) in [
+            ("dispute", TransactionType::Dispute),
+            ("resolve", TransactionType::Resolve),
+            ("chargeback", TransactionType::Chargeback),
+        ] {
+            for fields in [
+                &[r#type, "1", "2"][..],
+                &[r#type, "1", "2", ""],
+                &[r#type, "1", "2", "0.0"],
+            ] {
+                let tx = convert(fields).unwrap();
+                assert_eq!(
+                    (expected, 1, 2, Amount(0.)),
+                    (tx.r#type, tx.client, tx.tx, tx.amount)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn optional_idempotency_key_should_be_read() {
+        let keys: Vec<_> = deserialize_all(
+            "type, client, tx, amount, idempotency_key\n\
+             deposit, 1, 1, 5.0, evt-1\n\
+             deposit, 1, 2, 5.0,\n\
+             deposit, 1, 3, 5.0\n\
+             dispute, 1, 1, , evt-2\n",
+        )
+        .into_iter()
+        .map(|tx| tx.unwrap().idempotency_key)
+        .collect();
+
+        assert_eq!(
+            vec![
+                Some("evt-1".to_string()),
+                None,
+                None,
+                Some("evt-2".to_string())
+            ],
+            keys
+        );
+    }
+
+    #[test]
+    fn optional_timestamp_should_be_read() {
+        let timestamps: Vec<_> = deserialize_all(
+            "type, client, tx, amount, idempotency_key, timestamp\n\
+             deposit, 1, 1, 5.0, , 1700000000\n\
+             deposit, 1, 2, 5.0, evt-1,\n\
+             dispute, 1, 1, , , 1700000100\n",
+        )
+        .into_iter()
+        .map(|tx| tx.unwrap().timestamp)
+        .collect();
+
+        assert_eq!(vec![Some(1700000000), None, Some(1700000100)], timestamps);
+        assert!(matches!(
+            &deserialize_all("type, client, tx, amount\ndeposit, 1, 1, 5.0, , yesterday\n")[..],
+            [Err(RecordError::Invalid {
+                column: Some("timestamp"),
+                ..
+            })]
+        ));
+    }
+
+    #[test]
+    fn json_lines_without_deposit_amount_should_be_reported() {
+        assert!(matches!(
+            parse_json_line(r#"{"type": "deposit", "client": 1, "tx": 1}"#, 3),
+            Err(RecordError::EmptyAmount { line: Some(3), .. })
+        ));
+        assert!(matches!(
+            parse_json_line(r#"{"type": "deposit", "client": 1}"#, 4),
+            Err(RecordError::Json { line: 4, .. })
+        ));
+        assert!(matches!(
+            parse_json_line(r#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": -5}"#, 5),
+            Err(RecordError::BadAmount { line: Some(5), amount }) if amount == "-5"
+        ));
+    }
+}
@@ -0,0 +1,105 @@
+use crate::account::{Account, TransactionError};
+use crate::transaction::{Amount, ClientId, Transaction, TransactionId, TransactionType};
+
+/// Machine readable description of a rejected transaction
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RejectedTransaction {
+    pub tx: TransactionId,
+    pub client: ClientId,
+    pub r#type: TransactionType,
+    /// Name of the error variant, see `TransactionError::name`
+    pub error: String,
+}
+
+impl RejectedTransaction {
+    /// Describe rejection of `tx` with `error`
+    pub fn new(tx: &Transaction, error: &TransactionError) -> Self {
+        Self {
+            tx: tx.tx,
+            client: tx.client,
+            r#type: tx.r#type,
+            error: error.name().to_string(),
+        }
+    }
+}
+
+/// Accounts with total balance falling into a single tranche
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrancheStat {
+    /// Inclusive lower bound of the tranche, `None` for the lowest one
+    pub lower: Option<Amount>,
+    /// Exclusive upper bound of the tranche, `None` for the highest one
+    pub upper: Option<Amount>,
+    /// Number of accounts in the tranche
+    pub accounts: usize,
+    /// Sum of total balances of accounts in the tranche
+    pub total: Amount,
+}
+
+/// Bucket `accounts` by their total balance into tranches separated by `boundaries`
+///
+/// Boundaries are expected in ascending order, `n` boundaries give `n + 1` tranches
+pub fn tranches<'a>(
+    accounts: impl Iterator<Item = &'a Account>,
+    boundaries: &[Amount],
+) -> Vec<TrancheStat> {
+    let mut tranches: Vec<TrancheStat> = (0..=boundaries.len())
+        .map(|i| TrancheStat {
+            lower: i.checked_sub(1).map(|i| boundaries[i]),
+            upper: boundaries.get(i).copied(),
+            accounts: 0,
+            total: Amount(0.),
+        })
+        .collect();
+    for account in accounts {
+        let i = boundaries.partition_point(|boundary| *boundary <= account.total());
+        tranches[i].accounts += 1;
+        tranches[i].total += account.total();
+    }
+    tranches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::ClientId;
+
+    #[test]
+    fn accounts_should_be_bucketed_by_total() {
+        let accounts: Vec<Account> = [0., 50., 100., 999.5, 1500.]
+            .into_iter()
+            .enumerate()
+            .map(|(id, total)| {
+                let mut account = Account::new(id as ClientId);
+                account.credit(Amount(total));
+                account
+            })
+            .collect();
+
+        let tranches = tranches(accounts.iter(), &[Amount(100.), Amount(1000.)]);
+
+        assert_eq!(
+            vec![
+                TrancheStat {
+                    lower: None,
+                    upper: Some(Amount(100.)),
+                    accounts: 2,
+                    total: Amount(50.),
+                },
+                TrancheStat {
+                    lower: Some(Amount(100.)),
+                    upper: Some(Amount(1000.)),
+                    accounts: 2,
+                    total: Amount(1099.5),
+                },
+                TrancheStat {
+                    lower: Some(Amount(1000.)),
+                    upper: None,
+                    accounts: 1,
+                    total: Amount(1500.),
+                },
+            ],
+            tranches
+        );
+    }
+}
@@ -0,0 +1,2412 @@
+use ed25519_dalek::{Signature, Signer, SigningKey};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::account::{
+    Account, AccountPolicy, TransactionError, TransactionOutcome, TransactionResult,
+};
+use crate::fixed_width::{FixedWidthError, FixedWidthLayout};
+use crate::output::{self, AmountStyle, ColumnNames, OutputOptions};
+#[cfg(feature = "async")]
+use crate::record::RecordError;
+use crate::report::{self, TrancheStat};
+use crate::source::{SourceError, TransactionSource};
+use crate::store::{AccountStore, StoreError, StoreResult};
+use crate::transaction::{
+    Amount, AmountOverflow, ClientId, RoundingMode, Transaction, TransactionId, TransactionType,
+};
+
+/// Tolerance of comparing the ledger with balances, differences of float errors of summing
+/// balances grow with the number of transactions
+const LEDGER_TOLERANCE: f64 = 1e-6;
+
+/// An exchanging service is a container for all created user accounts
+///
+/// It handles dispatching transactions to correct accounts as well as
+/// creating new accounts where needed. Accounts are kept in memory unless
+/// another `AccountStore` is given with `ServiceBuilder::build_with_store`.
+///
+/// A cloned service can be used to try out transactions without affecting the original,
+/// hooks registered with `on_applied` and `on_hold` and the observer are not carried over
+/// to the clone though.
+#[derive(Debug, Default, Clone)]
+pub struct Service<S = HashMap<ClientId, Account>> {
+    accounts: S,
+    output: OutputOptions,
+    policy: AccountPolicy,
+    chargeback_destination: Option<ClientId>,
+    invariant_check_interval: Option<usize>,
+    invariant_check: InvariantCheck,
+    stats: Stats,
+    suspicious_activity: Vec<SuspiciousActivity>,
+    invariant_violations: Vec<InvariantViolation>,
+    audit_log: Option<Vec<AuditEntry>>,
+    hooks: Hooks,
+    hold_hook: BoxedHoldHook,
+    observer: BoxedObserver,
+    replay_guard: Option<ReplayGuard>,
+    unknown_clients: UnknownClientPolicy,
+    /// Highest id of applied deposit or withdrawal per client, tracked for `replay_guard`
+    last_applied: HashMap<ClientId, TransactionId>,
+    /// Idempotency keys of applied transactions per client, see `Transaction::idempotency_key`
+    idempotency_keys: HashSet<(ClientId, String)>,
+    ledger: Ledger,
+}
+
+/// Handling of deposits and withdrawals replayed by an at-least-once delivery
+///
+/// Transaction ids of each client are assumed to increase, so a deposit or withdrawal
+/// with id not greater than the highest applied one of its client is a replay.
+/// Disputes, resolves and chargebacks reference earlier transactions and aren't guarded.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReplayGuard {
+    /// Reject replayed transactions with `TransactionError::OutOfOrder`
+    Reject,
+    /// Ignore replayed transactions, as if they were never processed
+    Skip,
+}
+
+/// Handling of transactions of clients without an account
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum UnknownClientPolicy {
+    /// Open an account for a deposit or withdrawal of a new client, disputes, resolves
+    /// and chargebacks of a new client are rejected with `TransactionError::NotFound`
+    #[default]
+    Create,
+    /// Only open accounts with deposits, reject anything else with
+    /// `TransactionError::UnknownClient` without creating an account
+    Reject,
+}
+
+/// Handling of rejected transactions when applying many of them, see `Service::apply_all`
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Carry on with the remaining transactions, collecting errors
+    #[default]
+    ContinueOnError,
+    /// Stop at the first rejected transaction
+    StopOnFirstError,
+}
+
+/// Callback fired after a transaction was successfully applied to an account
+pub type Hook = Box<dyn FnMut(&Transaction, &Account)>;
+
+/// Callbacks registered per type of transaction
+#[derive(Default)]
+struct Hooks(HashMap<TransactionType, Vec<Hook>>);
+
+// Boxed callbacks can't be cloned, a clone of a service starts without hooks
+impl Clone for Hooks {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map()
+            .entries(self.0.iter().map(|(r#type, hooks)| (r#type, hooks.len())))
+            .finish()
+    }
+}
+
+/// Callback fired when a dispute moves funds to held, see `Service::on_hold`
+///
+/// It is given the id of the disputed transaction and the amount that got held, and returns
+/// an adjustment of available funds of the account, eg. a negative fee
+pub type HoldHook = Box<dyn FnMut(TransactionId, Amount) -> Amount>;
+
+/// Hold hook of a service, by default adjusting nothing
+struct BoxedHoldHook(HoldHook);
+
+impl Default for BoxedHoldHook {
+    fn default() -> Self {
+        Self(Box::new(|_, _| Amount(0.)))
+    }
+}
+
+// Like hooks, the hold hook can't be cloned, a clone of a service starts with the default one
+impl Clone for BoxedHoldHook {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl std::fmt::Debug for BoxedHoldHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("HoldHook")
+    }
+}
+
+/// Observer of outcomes of all the transactions applied by a service, eg. to collect metrics
+///
+/// Both methods do nothing by default, so that an observer implements only what it needs.
+/// Transactions skipped by `ReplayGuard::Skip` are not observed.
+pub trait Observer {
+    /// Called after `tx` was successfully applied, with the state of the account after it
+    fn applied(&mut self, _tx: &Transaction, _account: &Account) {}
+
+    /// Called after `tx` was rejected with `error`
+    fn rejected(&mut self, _tx: &Transaction, _error: &TransactionError) {}
+}
+
+/// Observer ignoring everything, used by services no observer was given to
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopObserver;
+
+impl Observer for NoopObserver {}
+
+/// Observer of a service, `NoopObserver` by default
+struct BoxedObserver(Box<dyn Observer>);
+
+impl Default for BoxedObserver {
+    fn default() -> Self {
+        Self(Box::new(NoopObserver))
+    }
+}
+
+// Like hooks, an observer can't be cloned, a clone of a service starts with the default one
+impl Clone for BoxedObserver {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl std::fmt::Debug for BoxedObserver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Observer")
+    }
+}
+
+/// Record of a single transaction processed by a service
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    /// Position of the transaction among all the transactions processed by the service,
+    /// starting from 0, which allows correlating entries with the input
+    pub seq: u64,
+    pub transaction: Transaction,
+    /// Name of the error the transaction was rejected with, see `TransactionError::name`
+    pub error: Option<&'static str>,
+}
+
+/// Attempt of a client to reference transaction of another client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SuspiciousActivity {
+    /// Type of the offending transaction
+    pub r#type: TransactionType,
+    /// Client that referenced the transaction
+    pub client: ClientId,
+    /// Referenced transaction
+    pub tx: TransactionId,
+    /// Client owning the referenced transaction
+    pub owner: ClientId,
+}
+
+/// Handling of accounts found breaking their invariants by periodic checks, see
+/// `ServiceBuilder::invariant_check_interval`
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum InvariantCheck {
+    /// Log the violation and keep processing, the transaction keeps its own result
+    #[default]
+    Warn,
+    /// Return `TransactionError::InvariantViolated` for the transaction that triggered
+    /// the check and refuse any further transactions, stopping batches and streams
+    Abort,
+}
+
+/// Account found breaking its invariants by a periodic check, see
+/// `ServiceBuilder::invariant_check_interval`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvariantViolation {
+    /// Transaction applied right before the check
+    pub tx: TransactionId,
+    /// Client of the first account found breaking its invariants
+    pub client: ClientId,
+}
+
+/// Summary of transactions processed by a service
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// Number of successfully applied transactions per type
+    pub applied: BTreeMap<TransactionType, usize>,
+    /// Number of rejected transactions per type
+    pub rejected: BTreeMap<TransactionType, usize>,
+    /// Number of rejected transactions per error, see `TransactionError::name`
+    pub errors: BTreeMap<&'static str, usize>,
+}
+
+impl Stats {
+    /// Get number of all processed transactions
+    pub fn processed(&self) -> usize {
+        self.applied.values().chain(self.rejected.values()).sum()
+    }
+
+    /// Count outcome of applying `tx`
+    fn record(&mut self, tx: &Transaction, result: &TransactionResult<TransactionOutcome>) {
+        match result {
+            Ok(_) => *self.applied.entry(tx.r#type).or_default() += 1,
+            Err(e) => {
+                *self.rejected.entry(tx.r#type).or_default() += 1;
+                *self.errors.entry(e.name()).or_default() += 1;
+            }
+        }
+    }
+
+    /// Add counters of `other` to these
+    fn merge(&mut self, other: Stats) {
+        for (r#type, count) in other.applied {
+            *self.applied.entry(r#type).or_default() += count;
+        }
+        for (r#type, count) in other.rejected {
+            *self.rejected.entry(r#type).or_default() += count;
+        }
+        for (error, count) in other.errors {
+            *self.errors.entry(error).or_default() += count;
+        }
+    }
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rejected: usize = self.rejected.values().sum();
+        writeln!(
+            f,
+            "processed {} transactions, {} rejected",
+            self.processed(),
+            rejected
+        )?;
+        for (r#type, count) in &self.applied {
+            writeln!(f, "applied {}: {count}", r#type.as_str())?;
+        }
+        for (r#type, count) in &self.rejected {
+            writeln!(f, "rejected {}: {count}", r#type.as_str())?;
+        }
+        for (error, count) in &self.errors {
+            writeln!(f, "error {error}: {count}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Net flows of funds through a service, tracked apart from balances of accounts
+///
+/// Sum of totals of all the accounts should always match `Ledger::net`, see
+/// `Service::check_ledger`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Ledger {
+    /// Sum of totals of accounts the service started with, eg. restored from a snapshot
+    pub opening: Amount,
+    /// Sum of applied deposits
+    pub deposited: Amount,
+    /// Sum of applied withdrawals that weren't charged back
+    pub withdrawn: Amount,
+    /// Sum of charged back deposits whose funds left the service
+    pub charged_back: Amount,
+    /// Sum of withdrawals under dispute, whose funds are held again
+    pub disputed_withdrawals: Amount,
+    /// Sum of adjustments of available funds returned by the hold hook, eg. fees
+    pub hold_adjustments: Amount,
+}
+
+impl Ledger {
+    /// Add flows of `other` to these
+    fn merge(&mut self, other: Ledger) {
+        self.opening += other.opening;
+        self.deposited += other.deposited;
+        self.withdrawn += other.withdrawn;
+        self.charged_back += other.charged_back;
+        self.disputed_withdrawals += other.disputed_withdrawals;
+        self.hold_adjustments += other.hold_adjustments;
+    }
+
+    /// Get the expected sum of totals of all the accounts
+    pub fn net(&self) -> Amount {
+        Amount(
+            self.opening.0 + self.deposited.0 - self.withdrawn.0 - self.charged_back.0
+                + self.disputed_withdrawals.0
+                + self.hold_adjustments.0,
+        )
+    }
+
+    /// Count successfully applied `tx`, given the `stored` transaction of its id in history
+    ///
+    /// That is the transaction itself for deposits and withdrawals, which may be stored
+    /// with a different amount, eg. when partially filled, or the referenced transaction
+    /// for disputes, resolves and chargebacks. Funds of charged back deposits routed
+    /// to a chargeback destination stay in the service.
+    fn record(&mut self, tx: &Transaction, stored: Option<&Transaction>, routed: bool) {
+        let stored = stored.map(|stored| (stored.r#type, stored.amount));
+        let amount = stored.map_or(tx.amount, |(_, amount)| amount);
+        match (tx.r#type, stored) {
+            (TransactionType::Deposit, _) => self.deposited += amount,
+            (TransactionType::Withdrawal, _) => self.withdrawn += amount,
+            (TransactionType::Chargeback, Some((TransactionType::Deposit, amount))) if !routed => {
+                self.charged_back += amount
+            }
+            (TransactionType::Dispute, Some((TransactionType::Withdrawal, amount))) => {
+                self.disputed_withdrawals += amount
+            }
+            (TransactionType::Resolve, Some((TransactionType::Withdrawal, amount))) => {
+                self.disputed_withdrawals -= amount
+            }
+            (TransactionType::Chargeback, Some((TransactionType::Withdrawal, amount))) => {
+                self.disputed_withdrawals -= amount;
+                self.withdrawn -= amount;
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Possible errors of merging services, see `Service::merge`
+#[derive(thiserror::Error, Debug)]
+pub enum MergeError {
+    #[error("Client `{0}` has an account in both merged services")]
+    ClientCollision(ClientId),
+    #[error("Failed to store merged account: {0}")]
+    Storage(#[from] StoreError),
+}
+
+/// Mismatch between the ledger of a service and balances of its accounts
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq)]
+#[error("Accounts hold {actual:?} in total, while the ledger expects {expected:?}")]
+pub struct LedgerDiscrepancy {
+    /// Net flow of funds according to the ledger
+    pub expected: Amount,
+    /// Sum of totals of all the accounts
+    pub actual: Amount,
+}
+
+impl LedgerDiscrepancy {
+    /// Get difference of the actual sum of totals from the expected one
+    pub fn difference(&self) -> Amount {
+        self.actual - self.expected
+    }
+}
+
+impl Service {
+    /// Create a new service
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a builder of a service with non-default configuration
+    pub fn builder() -> ServiceBuilder {
+        ServiceBuilder::default()
+    }
+}
+
+impl<S: AccountStore> Service<S> {
+    /// Dispatch a transaction to correct account and create one if it doesn't exist yet
+    ///
+    /// Transaction carrying an idempotency key of an already applied transaction of its
+    /// client is skipped, regardless of its id. Keys are kept in memory only.
+    pub fn apply(&mut self, tx: &Transaction) -> TransactionResult<()> {
+        self.apply_with_outcome(tx).map(|_| ())
+    }
+
+    /// Apply a transaction like `apply`, returning the account of its client after it
+    ///
+    /// Transaction skipped while its client had no account yet fails with
+    /// `TransactionError::UnknownClient`. To tell what the transaction did, use
+    /// `apply_with_outcome` followed by `account`.
+    pub fn apply_and_get(&mut self, tx: &Transaction) -> TransactionResult<&Account> {
+        self.apply_with_outcome(tx)?;
+        self.accounts
+            .get(tx.client)
+            .ok_or(TransactionError::UnknownClient(tx.client))
+    }
+
+    /// Apply a transaction like `apply`, telling if it locked the account or got skipped
+    pub fn apply_with_outcome(
+        &mut self,
+        tx: &Transaction,
+    ) -> TransactionResult<TransactionOutcome> {
+        if let Some(violation) = self.aborted() {
+            return Err(TransactionError::InvariantViolated(violation.client));
+        }
+        #[cfg(feature = "tracing")]
+        let _span =
+            tracing::debug_span!("apply", client = tx.client, tx = tx.tx, kind = ?tx.r#type)
+                .entered();
+        if self.replay_guard == Some(ReplayGuard::Skip) && self.is_replayed(tx) {
+            log::debug!("Skipping replayed transaction `{}`", tx.tx);
+            return Ok(TransactionOutcome::Ignored);
+        }
+        if let Some(key) = &tx.idempotency_key {
+            if self.idempotency_keys.contains(&(tx.client, key.clone())) {
+                log::debug!(
+                    "Skipping transaction `{}` with a seen idempotency key",
+                    tx.tx
+                );
+                return Ok(TransactionOutcome::Ignored);
+            }
+        }
+        let result = self.dispatch(tx);
+        let mut violated = None;
+        if let Some(interval) = self.invariant_check_interval {
+            if (self.stats.processed() + 1).is_multiple_of(interval) {
+                if let Err(TransactionError::InvariantViolated(client)) = self.check_invariants() {
+                    log::error!(
+                        "Account `{client}` breaks its invariants after transaction `{}`",
+                        tx.tx
+                    );
+                    self.invariant_violations
+                        .push(InvariantViolation { tx: tx.tx, client });
+                    violated = Some(client);
+                }
+            }
+        }
+        if let Some(audit_log) = self.audit_log.as_mut() {
+            audit_log.push(AuditEntry {
+                seq: self.stats.processed() as u64,
+                transaction: tx.clone(),
+                error: result.as_ref().err().map(TransactionError::name),
+            });
+        }
+        self.stats.record(tx, &result);
+        match &result {
+            Ok(_) => {
+                if let Some(key) = &tx.idempotency_key {
+                    self.idempotency_keys.insert((tx.client, key.clone()));
+                }
+                if let Some(account) = self.accounts.get(tx.client) {
+                    self.observer.0.applied(tx, account);
+                    if let Some(hooks) = self.hooks.0.get_mut(&tx.r#type) {
+                        hooks.iter_mut().for_each(|hook| hook(tx, account));
+                    }
+                }
+            }
+            Err(e) => self.observer.0.rejected(tx, e),
+        }
+        match violated {
+            Some(client) if self.invariant_check == InvariantCheck::Abort => {
+                Err(TransactionError::InvariantViolated(client))
+            }
+            _ => result,
+        }
+    }
+
+    /// Get the violation processing was aborted at, see `InvariantCheck::Abort`
+    pub fn aborted(&self) -> Option<&InvariantViolation> {
+        self.invariant_violations
+            .first()
+            .filter(|_| self.invariant_check == InvariantCheck::Abort)
+    }
+
+    /// Register `hook` fired after every successfully applied transaction of given type
+    ///
+    /// The hook is given the applied transaction and the state of the account after it
+    pub fn on_applied(&mut self, r#type: TransactionType, hook: Hook) {
+        self.hooks.0.entry(r#type).or_default().push(hook);
+    }
+
+    /// Set `hook` fired whenever a dispute moves funds to held, replacing the previous one
+    ///
+    /// Adjustment returned by the hook is added to available and total funds of the account,
+    /// which keeps fee policies out of the core, eg. `Box::new(|_, held| Amount(-0.01 * held.0))`
+    /// charges a fee of 1% of the disputed amount. The adjustment is made regardless of
+    /// the resulting balance and is not undone by resolving or charging back the dispute.
+    pub fn on_hold(&mut self, hook: HoldHook) {
+        self.hold_hook = BoxedHoldHook(hook);
+    }
+
+    /// Get entries of the audit log in processing order
+    ///
+    /// The log is empty unless enabled with `ServiceBuilder::audit_log`
+    pub fn audit_log(&self) -> &[AuditEntry] {
+        self.audit_log.as_deref().unwrap_or_default()
+    }
+
+    /// Get counters of applied and rejected transactions
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Get all the attempts of referencing transactions of other clients
+    pub fn suspicious_activity(&self) -> &[SuspiciousActivity] {
+        &self.suspicious_activity
+    }
+
+    /// Get all the violations of invariants found by periodic checks
+    pub fn invariant_violations(&self) -> &[InvariantViolation] {
+        &self.invariant_violations
+    }
+
+    /// Check if transaction `tx` of `client_id` is currently disputed
+    ///
+    /// Returns `None` if the client has no such transaction
+    pub fn is_disputed(&self, client_id: ClientId, tx: TransactionId) -> Option<bool> {
+        self.accounts.get(client_id)?.is_disputed(tx)
+    }
+
+    /// Unlock account of `client_id`, see `Account::unlock`
+    ///
+    /// Returns `false` if there is no such account
+    pub fn unlock(&mut self, client_id: ClientId) -> StoreResult<bool> {
+        let Some(account) = self.accounts.get_mut(client_id) else {
+            return Ok(false);
+        };
+        account.unlock();
+        self.accounts.persist(client_id, None)?;
+        Ok(true)
+    }
+
+    /// Void deposit `tx` of `client_id`, see `Account::void_deposit`
+    ///
+    /// Voided amount is taken off deposits of the ledger
+    pub fn void_deposit(
+        &mut self,
+        client_id: ClientId,
+        tx: TransactionId,
+    ) -> TransactionResult<()> {
+        let account = self
+            .accounts
+            .get_mut(client_id)
+            .ok_or(TransactionError::NotFound(tx))?;
+        let amount = account.void_deposit(tx)?;
+        // Voided deposit is gone from history, so the whole account is stored again
+        let account = account.clone();
+        self.accounts.insert(account)?;
+        self.ledger.deposited -= amount;
+        Ok(())
+    }
+
+    /// Check invariants of all the accounts, see `Account::check_invariants`
+    pub fn check_invariants(&self) -> TransactionResult<()> {
+        self.accounts().try_for_each(Account::check_invariants)
+    }
+
+    /// Move accounts of `other`, eg. a service that processed another shard of input, into this one
+    ///
+    /// Services are expected to hold disjoint sets of clients, an account of the same client
+    /// in both is reported as a collision and nothing gets merged. The only exception is
+    /// the `chargeback_destination` shared by both services, as long as the account only
+    /// received charged back funds in `other`, which are then credited to this one.
+    /// Moved accounts follow the policy of this service. Stats, ledgers, suspicious activity
+    /// and audit logs are combined, entries of the audit log of `other` are numbered on
+    /// from the transactions processed by this service. Configuration and hooks of `other`
+    /// are dropped.
+    pub fn merge(&mut self, other: Service) -> Result<(), MergeError> {
+        let shared_destination = self
+            .chargeback_destination
+            .filter(|&destination| other.chargeback_destination == Some(destination));
+        let credited_only = |account: &Account| {
+            Some(account.id()) == shared_destination
+                && account.transaction_count() == 0
+                && account.held() == Amount(0.)
+        };
+        if let Some(account) = other
+            .accounts()
+            .find(|account| self.accounts.get(account.id()).is_some() && !credited_only(account))
+        {
+            return Err(MergeError::ClientCollision(account.id()));
+        }
+        for (_, mut account) in other.accounts {
+            if let Some(destination) = self.accounts.get_mut(account.id()) {
+                destination.credit(account.available());
+                self.accounts.persist(account.id(), None)?;
+                continue;
+            }
+            account.set_policy(self.policy);
+            self.accounts.insert(account)?;
+        }
+        let processed = self.stats.processed() as u64;
+        self.stats.merge(other.stats);
+        self.ledger.merge(other.ledger);
+        self.suspicious_activity.extend(other.suspicious_activity);
+        self.invariant_violations.extend(other.invariant_violations);
+        if let (Some(audit_log), Some(other)) = (self.audit_log.as_mut(), other.audit_log) {
+            audit_log.extend(other.into_iter().map(|entry| AuditEntry {
+                seq: entry.seq + processed,
+                ..entry
+            }));
+        }
+        self.last_applied.extend(other.last_applied);
+        self.idempotency_keys.extend(other.idempotency_keys);
+        Ok(())
+    }
+
+    /// Get net flows of funds through the service
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    /// Check that sum of totals of all the accounts matches the ledger, see `Ledger::net`
+    ///
+    /// Differences within float errors of summing the balances are tolerated
+    pub fn check_ledger(&self) -> Result<(), LedgerDiscrepancy> {
+        let expected = self.ledger.net();
+        let actual: Amount = self.accounts().map(Account::total).sum();
+        if (actual.0 - expected.0).abs() < LEDGER_TOLERANCE {
+            Ok(())
+        } else {
+            Err(LedgerDiscrepancy { expected, actual })
+        }
+    }
+
+    /// Record references to transactions of other clients, which may indicate a fraud
+    ///
+    /// Such transaction is not found in the account of the client, so `NotFound` error
+    /// is replaced with a more descriptive `ClientMismatch`
+    fn report_foreign_tx(&mut self, tx: &Transaction, error: TransactionError) -> TransactionError {
+        let TransactionError::NotFound(tx_id) = error else {
+            return error;
+        };
+        let owner = self
+            .accounts()
+            .find(|account| account.has_tx(tx_id))
+            .map(Account::id);
+        match owner {
+            Some(owner) => {
+                self.suspicious_activity.push(SuspiciousActivity {
+                    r#type: tx.r#type,
+                    client: tx.client,
+                    tx: tx_id,
+                    owner,
+                });
+                TransactionError::ClientMismatch(tx_id)
+            }
+            None => error,
+        }
+    }
+
+    /// Fire the hold hook for funds held by dispute `tx`, applying the adjustment it returns
+    fn adjust_held(&mut self, tx: &Transaction, held_before: Amount) -> TransactionResult<()> {
+        let Some(account) = self.accounts.get_mut(tx.client) else {
+            return Ok(());
+        };
+        let held = account.held() - held_before;
+        if held <= Amount(0.) {
+            return Ok(());
+        }
+        let adjustment = (self.hold_hook.0)(tx.tx, held);
+        if adjustment != Amount(0.) {
+            account.credit(adjustment);
+            self.ledger.hold_adjustments += adjustment;
+            self.accounts.persist(tx.client, None)?;
+        }
+        Ok(())
+    }
+
+    /// Check if `tx` is a deposit or withdrawal with id not above the last applied one
+    fn is_replayed(&self, tx: &Transaction) -> bool {
+        tx.is_monetary()
+            && self
+                .last_applied
+                .get(&tx.client)
+                .is_some_and(|&last| tx.tx <= last)
+    }
+
+    /// Apply a transaction to its account and route charged back funds
+    fn dispatch(&mut self, tx: &Transaction) -> TransactionResult<TransactionOutcome> {
+        if self.replay_guard == Some(ReplayGuard::Reject) && self.is_replayed(tx) {
+            return Err(TransactionError::OutOfOrder(tx.tx));
+        }
+        if self.unknown_clients == UnknownClientPolicy::Reject
+            && tx.r#type != TransactionType::Deposit
+            && self.accounts.get(tx.client).is_none()
+        {
+            return Err(TransactionError::UnknownClient(tx.client));
+        }
+        // Disputes, resolves and chargebacks can only reference history of an existing
+        // account, so they never open one, which would show up empty in the output
+        if !tx.is_monetary() && self.accounts.get(tx.client).is_none() {
+            return Err(self.report_foreign_tx(tx, TransactionError::NotFound(tx.tx)));
+        }
+        if tx.r#type == TransactionType::Chargeback && !self.destination_available(tx) {
+            return Err(TransactionError::DestinationUnavailable(tx.tx));
+        }
+        let policy = self.policy;
+        let account = self
+            .accounts
+            .get_or_insert_with(tx.client, || Account::with_policy(tx.client, policy));
+        let (total_before, held_before) = (account.total(), account.held());
+        // Replacing a transaction, as allowed by `DuplicatePolicy`, undoes its flow of funds
+        let replaced = account
+            .transaction(tx.tx)
+            .and_then(|previous| (previous.r#type == tx.r#type).then_some(previous.amount))
+            .filter(|_| tx.is_monetary());
+        let result = account.apply(tx);
+        let removed = total_before - account.total();
+        // Rejected transactions may change the account too, eg. by locking it
+        self.accounts.persist(tx.client, Some(tx.tx))?;
+        let outcome = result.map_err(|e| self.report_foreign_tx(tx, e))?;
+        let stored = self
+            .accounts
+            .get(tx.client)
+            .and_then(|account| account.transaction(tx.tx));
+        match (tx.r#type, replaced) {
+            (TransactionType::Deposit, Some(amount)) => self.ledger.deposited -= amount,
+            (TransactionType::Withdrawal, Some(amount)) => self.ledger.withdrawn -= amount,
+            _ => (),
+        }
+        self.ledger
+            .record(tx, stored, self.chargeback_destination.is_some());
+        if tx.r#type == TransactionType::Dispute {
+            self.adjust_held(tx, held_before)?;
+        }
+        if self.replay_guard.is_some() && tx.is_monetary() {
+            let last = self.last_applied.entry(tx.client).or_insert(tx.tx);
+            *last = (*last).max(tx.tx);
+        }
+
+        // Only charging back a deposit removes funds from the ledger
+        if let (TransactionType::Chargeback, Some(destination)) =
+            (tx.r#type, self.chargeback_destination)
+        {
+            if removed > Amount(0.) {
+                self.accounts
+                    .get_or_insert_with(destination, || Account::with_policy(destination, policy))
+                    .credit(removed);
+                self.accounts.persist(destination, None)?;
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Check if funds removed by chargeback `tx` can be credited to `chargeback_destination`
+    ///
+    /// Only charging back a disputed deposit moves funds to the destination, which must not
+    /// be locked nor be the account of the charged back client
+    fn destination_available(&self, tx: &Transaction) -> bool {
+        let Some(destination) = self.chargeback_destination else {
+            return true;
+        };
+        let moves_funds = self.is_disputed(tx.client, tx.tx) == Some(true)
+            && self
+                .accounts
+                .get(tx.client)
+                .and_then(|account| account.transaction(tx.tx))
+                .is_some_and(|charged_back| charged_back.r#type == TransactionType::Deposit);
+        let usable = destination != tx.client
+            && !self.accounts.get(destination).is_some_and(Account::locked);
+        !moves_funds || usable
+    }
+
+    /// Apply all transactions from `txs` in order
+    ///
+    /// Failed transactions don't stop processing, their errors are returned
+    /// together with the index of the transaction in `txs`. Meant for transactions
+    /// parsed already, eg. handed over through FFI, as it skips the csv layer.
+    /// Processing stops once it is aborted, see `InvariantCheck::Abort`.
+    pub fn apply_slice(&mut self, txs: &[Transaction]) -> Vec<(usize, TransactionError)> {
+        let mut errors = Vec::new();
+        for (i, tx) in txs.iter().enumerate() {
+            if let Err(e) = self.apply(tx) {
+                errors.push((i, e));
+            }
+            if self.aborted().is_some() {
+                break;
+            }
+        }
+        errors
+    }
+
+    /// Apply all transactions from `txs` in order of their timestamps
+    ///
+    /// Meant for replaying history merged from separate feeds, eg. of deposits and later
+    /// disputes. Sorting is stable, so transactions with equal timestamps keep their order,
+    /// ones without a timestamp are applied first. Errors are returned together with
+    /// the index of the transaction in `txs`, like with `apply_slice`.
+    pub fn apply_sorted_by_time(
+        &mut self,
+        txs: impl IntoIterator<Item = Transaction>,
+    ) -> Vec<(usize, TransactionError)> {
+        let mut txs: Vec<_> = txs.into_iter().enumerate().collect();
+        txs.sort_by_key(|(_, tx)| tx.timestamp);
+        let mut errors = Vec::new();
+        for (i, tx) in &txs {
+            if let Err(e) = self.apply(tx) {
+                errors.push((*i, e));
+            }
+            if self.aborted().is_some() {
+                break;
+            }
+        }
+        errors.sort_by_key(|&(i, _)| i);
+        errors
+    }
+
+    /// Apply all transactions from `txs` in order, handling rejected ones following `policy`
+    ///
+    /// With `ErrorPolicy::ContinueOnError` errors are collected together with the index
+    /// of the transaction, like with `apply_slice`. With `ErrorPolicy::StopOnFirstError`
+    /// the first error ends processing and is returned, leaving the rest unapplied.
+    pub fn apply_all<'a>(
+        &mut self,
+        txs: impl IntoIterator<Item = &'a Transaction>,
+        policy: ErrorPolicy,
+    ) -> Result<Vec<(usize, TransactionError)>, (usize, TransactionError)> {
+        let mut errors = Vec::new();
+        for (i, tx) in txs.into_iter().enumerate() {
+            if let Err(e) = self.apply(tx) {
+                match policy {
+                    ErrorPolicy::ContinueOnError => errors.push((i, e)),
+                    ErrorPolicy::StopOnFirstError => return Err((i, e)),
+                }
+            }
+            if self.aborted().is_some() {
+                break;
+            }
+        }
+        Ok(errors)
+    }
+
+    /// Apply transactions of a possibly endless stream `txs`, eg. consumed from a message broker
+    ///
+    /// `checkpoint` is given the service after every `interval` processed transactions,
+    /// eg. to store its `snapshot`, and once more when the stream ends. Interval of 0
+    /// leaves only the final checkpoint. Errors aren't collected, as the stream may never
+    /// end, rejected transactions are counted in `stats` and reported to the observer.
+    /// The stream is left once processing is aborted, see `InvariantCheck::Abort`.
+    pub fn process_stream<I, F>(&mut self, txs: I, interval: usize, mut checkpoint: F)
+    where
+        I: IntoIterator<Item = Transaction>,
+        F: FnMut(&Self),
+    {
+        let mut since_checkpoint = 0;
+        for tx in txs {
+            // Rejections are counted and reported by `apply` itself
+            let _ = self.apply(&tx);
+            if self.aborted().is_some() {
+                break;
+            }
+            since_checkpoint += 1;
+            if since_checkpoint == interval {
+                checkpoint(self);
+                since_checkpoint = 0;
+            }
+        }
+        if since_checkpoint > 0 || interval == 0 {
+            checkpoint(self);
+        }
+    }
+
+    /// Apply all transactions yielded by `source` in order
+    ///
+    /// Failed transactions don't stop processing, their errors are returned together
+    /// with the index of the transaction. Reading stops at first error of the source.
+    pub fn apply_source<T: TransactionSource>(
+        &mut self,
+        mut source: T,
+    ) -> Result<Vec<(usize, TransactionError)>, SourceError> {
+        let mut errors = Vec::new();
+        let mut i = 0;
+        while let Some(tx) = source.next_transaction() {
+            if let Err(e) = self.apply(&tx?) {
+                errors.push((i, e));
+            }
+            if self.aborted().is_some() {
+                break;
+            }
+            i += 1;
+        }
+        Ok(errors)
+    }
+
+    /// Apply all transactions read from `reader` as records of fixed width `layout`
+    ///
+    /// Blank lines are skipped. Failed transactions don't stop processing, their errors are
+    /// returned together with the 1-based line number. Reading stops at first invalid record.
+    pub fn apply_fixed_width<R: std::io::Read>(
+        &mut self,
+        reader: R,
+        layout: &FixedWidthLayout,
+    ) -> Result<Vec<(usize, TransactionError)>, FixedWidthError> {
+        let mut errors = Vec::new();
+        for (i, line) in std::io::BufRead::lines(std::io::BufReader::new(reader)).enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Err(e) = self.apply(&layout.parse(&line, i + 1)?) {
+                errors.push((i + 1, e));
+            }
+            if self.aborted().is_some() {
+                break;
+            }
+        }
+        Ok(errors)
+    }
+
+    /// Apply all transactions read as csv from `reader` as they arrive
+    ///
+    /// Records are converted like the ones of csv input files, with
+    /// `TryFrom<csv::StringRecord> for Transaction`. Failed transactions don't stop processing,
+    /// their errors are returned together with the index of the record.
+    /// Reading stops at first record that isn't a valid transaction, returning its error.
+    #[cfg(feature = "async")]
+    pub async fn apply_async<R>(
+        &mut self,
+        reader: R,
+    ) -> Result<Vec<(usize, TransactionError)>, RecordError>
+    where
+        R: tokio::io::AsyncRead + Unpin + Send,
+    {
+        let mut reader = csv_async::AsyncReaderBuilder::new()
+            .trim(csv_async::Trim::All)
+            .flexible(true)
+            .create_reader(reader);
+        let mut record = csv_async::StringRecord::new();
+        let mut errors = Vec::new();
+        let mut i = 0;
+        while reader.read_record(&mut record).await? {
+            // Records are converted like the ones read synchronously, keeping their position
+            let mut converted: csv::StringRecord = record.iter().collect();
+            if let Some(position) = record.position() {
+                let mut sync_position = csv::Position::new();
+                sync_position
+                    .set_byte(position.byte())
+                    .set_line(position.line())
+                    .set_record(position.record());
+                converted.set_position(Some(sync_position));
+            }
+            let tx = Transaction::try_from(converted)?;
+            if let Err(e) = self.apply(&tx) {
+                errors.push((i, e));
+            }
+            if self.aborted().is_some() {
+                break;
+            }
+            i += 1;
+        }
+        Ok(errors)
+    }
+
+    /// Compute sha256 digest of the state of all the accounts, identifying results of a run
+    ///
+    /// Accounts are hashed in order of their ids, each as its id, available, held and total
+    /// funds rounded to current precision and the locked flag. Digest doesn't depend
+    /// on the order of accounts in the store nor on the platform.
+    pub fn state_digest(&self) -> [u8; 32] {
+        use sha2::Digest;
+
+        let mut accounts: Vec<_> = self.accounts().collect();
+        accounts.sort_unstable_by_key(|account| account.id());
+        let mut hasher = sha2::Sha256::new();
+        for account in accounts {
+            hasher.update(account.id().to_le_bytes());
+            for amount in [account.available(), account.held(), account.total()] {
+                hasher.update(amount.bits().to_le_bytes());
+            }
+            hasher.update([u8::from(account.locked())]);
+        }
+        hasher.finalize().into()
+    }
+
+    /// Get account of `client_id`, if it was created
+    pub fn account(&self, client_id: ClientId) -> Option<&Account> {
+        self.accounts.get(client_id)
+    }
+
+    /// Get all the accounts created so far
+    pub fn accounts(&self) -> impl Iterator<Item = &Account> {
+        self.accounts.accounts()
+    }
+
+    /// Sum held funds of all the accounts, failing if the sum overflows
+    pub fn total_held(&self) -> Result<Amount, AmountOverflow> {
+        self.accounts()
+            .try_fold(Amount(0.), |sum, account| sum.checked_add(account.held()))
+    }
+
+    /// Sum available funds of all the accounts, failing if the sum overflows
+    pub fn total_available(&self) -> Result<Amount, AmountOverflow> {
+        self.accounts().try_fold(Amount(0.), |sum, account| {
+            sum.checked_add(account.available())
+        })
+    }
+
+    /// Bucket accounts by their total balance, see `report::tranches`
+    pub fn tranche_report(&self, boundaries: &[Amount]) -> Vec<TrancheStat> {
+        report::tranches(self.accounts(), boundaries)
+    }
+
+    /// Write a snapshot of all the accounts, including their history, as json into `writer`
+    ///
+    /// Configuration of the service, its stats and audit log are not a part of the snapshot
+    pub fn snapshot<W: std::io::Write>(&self, writer: W) -> serde_json::Result<()> {
+        let mut accounts: Vec<_> = self.accounts().collect();
+        accounts.sort_by_key(|account| account.id());
+        serde_json::to_writer(writer, &accounts)
+    }
+
+    /// Replace all the accounts with ones read from a snapshot, see `Service::snapshot`
+    ///
+    /// Restored accounts follow the policy of this service. The ledger of the service
+    /// starts over from the totals of restored accounts.
+    pub fn restore<R: std::io::Read>(&mut self, reader: R) -> StoreResult<()> {
+        let mut accounts: Vec<Account> = serde_json::from_reader(reader)?;
+        accounts
+            .iter_mut()
+            .for_each(|account| account.set_policy(self.policy));
+        self.ledger = Ledger {
+            opening: accounts.iter().map(Account::total).sum(),
+            ..Ledger::default()
+        };
+        self.accounts.replace_all(accounts)
+    }
+
+    /// Write state of all the accounts as csv into `writer`
+    pub fn write_csv<W: std::io::Write>(&self, writer: W) -> csv::Result<()> {
+        output::write_csv(self.accounts(), &self.output, writer)
+    }
+
+    /// Write state of all the accounts into `writer` encoded with bincode, see `output::write_bincode`
+    #[cfg(feature = "bincode")]
+    pub fn write_bincode<W: std::io::Write>(
+        &self,
+        writer: W,
+    ) -> Result<(), bincode::error::EncodeError> {
+        output::write_bincode(self.accounts(), &self.output, writer)
+    }
+
+    /// Write state of all the accounts as csv into `writer` and sign the written bytes
+    ///
+    /// Returns an ed25519 signature of the whole output
+    pub fn write_signed_csv<W: std::io::Write>(
+        &self,
+        mut writer: W,
+        signing_key: &SigningKey,
+    ) -> csv::Result<Signature> {
+        let mut output = Vec::new();
+        self.write_csv(&mut output)?;
+        writer.write_all(&output)?;
+        Ok(signing_key.sign(&output))
+    }
+}
+
+/// Builder of a service with non-default configuration
+///
+/// Options that are not set keep their defaults, the same as of `Service::new`
+#[derive(Debug, Default)]
+pub struct ServiceBuilder {
+    service: Service,
+}
+
+impl ServiceBuilder {
+    /// Use given rounding `mode` when writing out amounts
+    pub fn rounding(mut self, mode: RoundingMode) -> Self {
+        self.service.output.rounding = mode;
+        self
+    }
+
+    /// Use given column `names` when writing out accounts
+    pub fn column_names(mut self, names: ColumnNames) -> Self {
+        self.service.output.column_names = names;
+        self
+    }
+
+    /// Write negative amounts in parentheses, following the accounting convention
+    pub fn accounting_negatives(mut self) -> Self {
+        self.service.output.accounting_negatives = true;
+        self
+    }
+
+    /// Quote all the fields when writing out accounts as csv
+    pub fn quote_all(mut self) -> Self {
+        self.service.output.quote_all = true;
+        self
+    }
+
+    /// Write out amounts in csv in given `style`
+    pub fn amount_style(mut self, style: AmountStyle) -> Self {
+        self.service.output.amount_style = style;
+        self
+    }
+
+    /// Skip unlocked accounts with all the balances at zero when writing out accounts
+    pub fn only_active(mut self) -> Self {
+        self.service.output.only_active = true;
+        self
+    }
+
+    /// Add activity of accounts to csv output, see `OutputOptions::extended`
+    pub fn extended_output(mut self) -> Self {
+        self.service.output.extended = true;
+        self
+    }
+
+    /// Report outcomes of all applied transactions to `observer`, see `Observer`
+    pub fn observer<O: Observer + 'static>(mut self, observer: O) -> Self {
+        self.service.observer = BoxedObserver(Box::new(observer));
+        self
+    }
+
+    /// Create new accounts following given `policy`
+    pub fn policy(mut self, policy: AccountPolicy) -> Self {
+        self.service.policy = policy;
+        self
+    }
+
+    /// Credit funds removed from accounts by chargebacks to account of `client_id`
+    ///
+    /// By default charged back funds simply leave the ledger. Chargebacks of deposits are
+    /// rejected with `TransactionError::DestinationUnavailable` while the destination is locked
+    /// and for the destination's own deposits, leaving the dispute open.
+    pub fn chargeback_destination(mut self, client_id: ClientId) -> Self {
+        self.service.chargeback_destination = Some(client_id);
+        self
+    }
+
+    /// Check invariants of all the accounts after every `interval` processed transactions
+    ///
+    /// Violations are collected in `Service::invariant_violations` and handled as given
+    /// with `invariant_check`, by default the transaction that triggered the check keeps
+    /// its own result. Interval of 0 disables the checks, which is also the default.
+    pub fn invariant_check_interval(mut self, interval: usize) -> Self {
+        self.service.invariant_check_interval = Some(interval).filter(|&interval| interval > 0);
+        self
+    }
+
+    /// Handle violations found by periodic checks of invariants following given `check`
+    pub fn invariant_check(mut self, check: InvariantCheck) -> Self {
+        self.service.invariant_check = check;
+        self
+    }
+
+    /// Handle transactions of clients without an account following given `policy`
+    pub fn unknown_clients(mut self, policy: UnknownClientPolicy) -> Self {
+        self.service.unknown_clients = policy;
+        self
+    }
+
+    /// Guard against deposits and withdrawals replayed by the source of transactions
+    pub fn replay_guard(mut self, guard: ReplayGuard) -> Self {
+        self.service.replay_guard = Some(guard);
+        self
+    }
+
+    /// Keep an audit log of all processed transactions, see `Service::audit_log`
+    pub fn audit_log(mut self) -> Self {
+        self.service.audit_log = Some(Vec::new());
+        self
+    }
+
+    /// Create the configured service
+    pub fn build(self) -> Service {
+        self.service
+    }
+
+    /// Create the configured service operating on accounts of given `store`
+    ///
+    /// Accounts already in the store follow the policy of the service from now on,
+    /// their totals are the opening balance of the ledger
+    pub fn build_with_store<S: AccountStore>(self, mut store: S) -> Service<S> {
+        let ids: Vec<_> = store.accounts().map(Account::id).collect();
+        for id in ids {
+            if let Some(account) = store.get_mut(id) {
+                account.set_policy(self.service.policy);
+            }
+        }
+        let Service {
+            accounts: _,
+            output,
+            policy,
+            chargeback_destination,
+            invariant_check_interval,
+            invariant_check,
+            stats,
+            suspicious_activity,
+            invariant_violations,
+            audit_log,
+            hooks,
+            hold_hook,
+            observer,
+            replay_guard,
+            unknown_clients,
+            last_applied,
+            idempotency_keys,
+            ledger: _,
+        } = self.service;
+        let ledger = Ledger {
+            opening: store.accounts().map(Account::total).sum(),
+            ..Ledger::default()
+        };
+        Service {
+            accounts: store,
+            output,
+            policy,
+            chargeback_destination,
+            invariant_check_interval,
+            invariant_check,
+            stats,
+            suspicious_activity,
+            invariant_violations,
+            audit_log,
+            hooks,
+            hold_hook,
+            observer,
+            replay_guard,
+            unknown_clients,
+            last_applied,
+            idempotency_keys,
+            ledger,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::DuplicatePolicy;
+    use crate::testutil::{chargeback, deposit, dispute, resolve, transaction, withdrawal};
+
+    #[test]
+    fn written_amounts_should_follow_rounding_mode() {
+        let mut service = Service::builder().rounding(RoundingMode::HalfEven).build();
+        service.apply(&deposit(1, 1, 1.00025)).unwrap();
+
+        let mut output = Vec::new();
+        service.write_csv(&mut output).unwrap();
+
+        assert_eq!(
+            "id,available,held,total,locked\n1,1.0002,0.0,1.0002,false\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn apply_slice_should_report_failures_at_their_indices() {
+        let txs = [
+            deposit(1, 1, 5.),
+            withdrawal(1, 2, 10.),
+            deposit(2, 3, 1.),
+            dispute(2, 4),
+            dispute(1, 1),
+        ];
+        let mut service = Service::new();
+
+        let errors = service.apply_slice(&txs);
+
+        assert_eq!(2, errors.len());
+        assert!(matches!(
+            errors[0],
+            (1, TransactionError::UnsufficientFunds(2))
+        ));
+        assert!(matches!(errors[1], (3, TransactionError::NotFound(4))));
+        assert_eq!(Amount(5.), service.accounts[&1].held());
+    }
+
+    #[test]
+    fn totals_of_accounts_should_sum_up() {
+        let mut service = Service::new();
+        [(1, 1, 1.5), (2, 2, 2.), (3, 3, 0.25), (1, 4, 1.)]
+            .into_iter()
+            .for_each(|(client, tx, amount)| {
+                service.apply(&deposit(client, tx, amount)).unwrap();
+            });
+
+        let total: Amount = service.accounts().map(|a| a.total()).sum();
+
+        assert_eq!(Amount(4.75), total);
+    }
+
+    #[test]
+    fn charged_back_funds_should_be_credited_to_destination() {
+        let mut service = Service::builder().chargeback_destination(9).build();
+        service.apply(&deposit(1, 1, 5.)).unwrap();
+        service.apply(&deposit(1, 2, 3.)).unwrap();
+        service.apply(&dispute(1, 1)).unwrap();
+
+        service.apply(&chargeback(1, 1)).unwrap();
+
+        assert_eq!(Amount(3.), service.accounts[&1].total());
+        assert_eq!(Amount(5.), service.accounts[&9].available());
+        assert_eq!(Amount(5.), service.accounts[&9].total());
+        let total: Amount = service.accounts().map(|a| a.total()).sum();
+        assert_eq!(Amount(8.), total);
+    }
+
+    #[test]
+    fn chargeback_to_locked_destination_should_be_rejected() {
+        let mut service = Service::builder().chargeback_destination(9).build();
+        // Charging back a withdrawal doesn't move funds to the destination, but locks it
+        let errors = service.apply_slice(&[
+            deposit(9, 1, 2.),
+            withdrawal(9, 2, 1.),
+            dispute(9, 2),
+            chargeback(9, 2),
+            deposit(1, 3, 5.),
+            dispute(1, 3),
+        ]);
+        assert!(errors.is_empty());
+        assert!(service.accounts[&9].locked());
+
+        assert!(matches!(
+            service.apply(&chargeback(1, 3)),
+            Err(TransactionError::DestinationUnavailable(3))
+        ));
+        assert_eq!(Amount(5.), service.accounts[&1].held());
+        assert!(!service.accounts[&1].locked());
+    }
+
+    #[test]
+    fn chargeback_of_destination_own_deposit_should_be_rejected() {
+        let mut service = Service::builder().chargeback_destination(9).build();
+        service.apply_slice(&[deposit(9, 1, 3.), dispute(9, 1)]);
+
+        assert!(matches!(
+            service.apply(&chargeback(9, 1)),
+            Err(TransactionError::DestinationUnavailable(1))
+        ));
+        assert_eq!(Amount(3.), service.accounts[&9].held());
+        assert_eq!(Amount(3.), service.accounts[&9].total());
+        service.apply(&resolve(9, 1)).unwrap();
+        assert_eq!(Amount(3.), service.accounts[&9].available());
+    }
+
+    #[test]
+    fn periodic_invariant_check_should_catch_corrupted_account() {
+        let mut service = Service::builder().invariant_check_interval(3).build();
+        service.apply(&deposit(1, 1, 1.)).unwrap();
+        let corrupted: Account = serde_json::from_str(
+            r#"{"id":2,"available":1.0,"held":0.0,"total":5.0,"locked":false}"#,
+        )
+        .unwrap();
+        service.accounts.insert(2, corrupted);
+
+        service.apply(&deposit(1, 2, 1.)).unwrap();
+        assert!(service.invariant_violations().is_empty());
+
+        service.apply(&deposit(1, 3, 1.)).unwrap();
+        assert_eq!(
+            [InvariantViolation { tx: 3, client: 2 }],
+            service.invariant_violations()
+        );
+        assert_eq!(Amount(3.), service.accounts[&1].total());
+        assert!(service.stats().rejected.is_empty());
+        // Warning keeps processing going
+        assert!(service.aborted().is_none());
+        service.apply(&deposit(1, 4, 1.)).unwrap();
+    }
+
+    #[test]
+    fn failed_invariant_check_should_abort_processing_if_configured() {
+        let corrupted = || -> Account {
+            serde_json::from_str(
+                r#"{"id":2,"available":1.0,"held":0.0,"total":5.0,"locked":false}"#,
+            )
+            .unwrap()
+        };
+        let txs = [
+            deposit(1, 1, 1.),
+            deposit(1, 2, 1.),
+            deposit(1, 3, 1.),
+            deposit(1, 4, 1.),
+        ];
+        let aborting = || {
+            let mut service = Service::builder()
+                .invariant_check_interval(2)
+                .invariant_check(InvariantCheck::Abort)
+                .build();
+            service.accounts.insert(2, corrupted());
+            service
+        };
+
+        let mut service = aborting();
+        let errors = service.apply_slice(&txs);
+        assert!(matches!(
+            errors[..],
+            [(1, TransactionError::InvariantViolated(2))]
+        ));
+        assert_eq!(
+            Some(&InvariantViolation { tx: 2, client: 2 }),
+            service.aborted()
+        );
+        // The triggering transaction got applied, the rest is refused
+        assert_eq!(Amount(2.), service.accounts[&1].total());
+        assert!(matches!(
+            service.apply(&deposit(1, 5, 1.)),
+            Err(TransactionError::InvariantViolated(2))
+        ));
+
+        let mut service = aborting();
+        let mut checkpoints = 0;
+        service.process_stream(txs.clone(), 0, |_| checkpoints += 1);
+        assert_eq!(Amount(2.), service.accounts[&1].total());
+        assert_eq!(2, service.stats().processed());
+        assert_eq!(1, checkpoints);
+    }
+
+    #[test]
+    fn stats_should_count_applied_and_rejected_transactions() {
+        let mut service = Service::new();
+        service.apply_slice(&[
+            deposit(1, 1, 5.),
+            deposit(2, 2, 1.),
+            withdrawal(1, 3, 2.),
+            withdrawal(2, 4, 2.),
+            dispute(1, 1),
+            dispute(2, 2),
+            dispute(2, 2),
+            resolve(1, 9),
+        ]);
+
+        let stats = service.stats();
+
+        assert_eq!(8, stats.processed());
+        assert_eq!(
+            BTreeMap::from([
+                (TransactionType::Deposit, 2),
+                (TransactionType::Withdrawal, 1),
+                (TransactionType::Dispute, 1),
+            ]),
+            stats.applied
+        );
+        assert_eq!(
+            BTreeMap::from([
+                (TransactionType::Withdrawal, 1),
+                (TransactionType::Dispute, 2),
+                (TransactionType::Resolve, 1),
+            ]),
+            stats.rejected
+        );
+        assert_eq!(
+            BTreeMap::from([
+                ("UnsufficientFunds", 2),
+                ("AlreadyDisputed", 1),
+                ("NotFound", 1),
+            ]),
+            stats.errors
+        );
+    }
+
+    #[test]
+    fn cross_client_disputes_should_be_recorded() {
+        let mut service = Service::new();
+        service.apply_slice(&[deposit(1, 1, 5.), deposit(2, 2, 5.)]);
+
+        let errors = service.apply_slice(&[
+            dispute(2, 1),
+            dispute(3, 1),
+            chargeback(1, 2),
+            dispute(1, 7),
+        ]);
+
+        assert_eq!(4, errors.len());
+        assert!(matches!(errors[3], (3, TransactionError::NotFound(7))));
+        assert_eq!(
+            &[
+                SuspiciousActivity {
+                    r#type: TransactionType::Dispute,
+                    client: 2,
+                    tx: 1,
+                    owner: 1,
+                },
+                SuspiciousActivity {
+                    r#type: TransactionType::Dispute,
+                    client: 3,
+                    tx: 1,
+                    owner: 1,
+                },
+                SuspiciousActivity {
+                    r#type: TransactionType::Chargeback,
+                    client: 1,
+                    tx: 2,
+                    owner: 2,
+                },
+            ],
+            service.suspicious_activity()
+        );
+        assert_eq!(Amount(0.), service.accounts[&1].held());
+    }
+
+    #[test]
+    fn audit_entries_should_be_numbered_in_input_order() {
+        let txs = [
+            deposit(1, 1, 5.),
+            deposit(2, 2, 3.),
+            withdrawal(2, 3, 4.),
+            dispute(1, 1),
+            deposit(3, 4, 1.),
+        ];
+        let mut service = Service::builder().audit_log().build();
+
+        service.apply_slice(&txs);
+
+        let entries: Vec<_> = service
+            .audit_log()
+            .iter()
+            .map(|entry| {
+                (
+                    entry.seq,
+                    entry.transaction.client,
+                    entry.transaction.tx,
+                    entry.error,
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (0, 1, 1, None),
+                (1, 2, 2, None),
+                (2, 2, 3, Some("UnsufficientFunds")),
+                (3, 1, 1, None),
+                (4, 3, 4, None),
+            ],
+            entries
+        );
+    }
+
+    #[test]
+    fn fixed_width_errors_should_be_reported_with_line_numbers() {
+        let layout = FixedWidthLayout {
+            r#type: 10,
+            client: 3,
+            tx: 3,
+            amount: 8,
+        };
+        let input = "deposit     1  1     5.0\n\
+                     \n\
+                     withdrawal  1  2     9.0\n\
+                     dispute     1  1\n";
+        let mut service = Service::new();
+
+        let errors = service
+            .apply_fixed_width(input.as_bytes(), &layout)
+            .unwrap();
+
+        assert_eq!(1, errors.len());
+        assert!(matches!(
+            errors[0],
+            (3, TransactionError::UnsufficientFunds(2))
+        ));
+        assert_eq!(Amount(5.), service.accounts[&1].held());
+    }
+
+    #[test]
+    fn builder_defaults_should_match_new_service() {
+        let built = Service::builder().build();
+        let new = Service::new();
+
+        assert_eq!(new.policy.allow_overdraft, built.policy.allow_overdraft);
+        assert_eq!(new.output.column_names, built.output.column_names);
+        assert_eq!(new.chargeback_destination, built.chargeback_destination);
+        assert!(built.audit_log.is_none());
+    }
+
+    #[test]
+    fn builder_options_should_take_effect() {
+        let mut service = Service::builder()
+            .policy(AccountPolicy {
+                allow_overdraft: true,
+                ..AccountPolicy::default()
+            })
+            .column_names(ColumnNames {
+                total: "balance".to_string(),
+                ..ColumnNames::default()
+            })
+            .build();
+
+        service.apply(&withdrawal(1, 1, 2.)).unwrap();
+
+        let mut output = Vec::new();
+        service.write_csv(&mut output).unwrap();
+        assert_eq!(
+            "id,available,held,balance,locked\n1,-2.0,0.0,-2.0,false\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn service_should_unlock_existing_accounts_only() {
+        let mut service = Service::new();
+        service.apply(&deposit(1, 1, 5.)).unwrap();
+        service.apply(&dispute(1, 1)).unwrap();
+        service.apply(&chargeback(1, 1)).unwrap();
+
+        assert!(service.unlock(1).unwrap());
+        assert!(!service.unlock(2).unwrap());
+        service.apply(&deposit(1, 2, 2.)).unwrap();
+        assert_eq!(Amount(2.), service.accounts[&1].available());
+    }
+
+    #[test]
+    fn disputed_state_of_transactions_should_be_reported() {
+        let mut service = Service::new();
+        service.apply(&deposit(1, 1, 5.)).unwrap();
+        service.apply(&dispute(1, 1)).unwrap();
+
+        assert_eq!(Some(true), service.is_disputed(1, 1));
+        service.apply(&resolve(1, 1)).unwrap();
+        assert_eq!(Some(false), service.is_disputed(1, 1));
+        assert_eq!(None, service.is_disputed(1, 2));
+        assert_eq!(None, service.is_disputed(2, 1));
+    }
+
+    #[test]
+    fn signed_output_should_verify_with_public_key() {
+        use ed25519_dalek::Verifier;
+
+        let mut service = Service::new();
+        service.apply(&deposit(1, 1, 5.)).unwrap();
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+
+        let mut output = Vec::new();
+        let signature = service.write_signed_csv(&mut output, &signing_key).unwrap();
+
+        let verifying_key = signing_key.verifying_key();
+        assert!(verifying_key.verify(&output, &signature).is_ok());
+        output[0] ^= 1;
+        assert!(verifying_key.verify(&output, &signature).is_err());
+    }
+
+    #[test]
+    fn hook_should_fire_after_applied_transaction_of_its_type() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let fired = Rc::new(RefCell::new(Vec::new()));
+        let mut service = Service::new();
+        service.on_applied(TransactionType::Chargeback, {
+            let fired = Rc::clone(&fired);
+            Box::new(move |tx, account| fired.borrow_mut().push((tx.tx, account.locked())))
+        });
+
+        service.apply(&deposit(1, 1, 5.)).unwrap();
+        service.apply(&chargeback(1, 1)).unwrap_err();
+        service.apply(&dispute(1, 1)).unwrap();
+        service.apply(&chargeback(1, 1)).unwrap();
+        service.apply(&chargeback(1, 1)).unwrap_err();
+
+        assert_eq!(vec![(1, true)], *fired.borrow());
+    }
+
+    #[test]
+    fn restored_service_should_dispute_transactions_from_before_snapshot() {
+        let mut service = Service::new();
+        service.apply(&deposit(1, 1, 5.)).unwrap();
+        service.apply(&deposit(1, 2, 2.5)).unwrap();
+        service.apply(&deposit(2, 3, 1.)).unwrap();
+        let mut snapshot = Vec::new();
+        service.snapshot(&mut snapshot).unwrap();
+
+        let mut restored = Service::new();
+        restored.restore(&snapshot[..]).unwrap();
+        restored.apply(&dispute(1, 1)).unwrap();
+
+        let account = restored.account(1).unwrap();
+        assert_eq!(Amount(5.), account.held());
+        assert_eq!(Amount(2.5), account.available());
+        assert_eq!(Amount(1.), restored.account(2).unwrap().total());
+        assert!(matches!(
+            restored.apply(&deposit(2, 3, 1.)),
+            Err(TransactionError::AlreadyExist(3))
+        ));
+    }
+
+    #[test]
+    fn totals_should_sum_all_accounts() {
+        let mut service = Service::new();
+        service.apply(&deposit(1, 1, 5.)).unwrap();
+        service.apply(&deposit(2, 2, 2.5)).unwrap();
+        service.apply(&deposit(3, 3, 1.)).unwrap();
+        service.apply(&dispute(2, 2)).unwrap();
+        service.apply(&withdrawal(3, 4, 0.25)).unwrap();
+
+        assert_eq!(Ok(Amount(2.5)), service.total_held());
+        assert_eq!(Ok(Amount(5.75)), service.total_available());
+
+        service.apply(&deposit(4, 5, f64::MAX)).unwrap();
+        service.apply(&deposit(5, 6, f64::MAX)).unwrap();
+        assert_eq!(Err(AmountOverflow), service.total_available());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn transactions_should_be_applied_from_async_reader() {
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 5.0\n\
+                   withdrawal, 1, 2, 7.5\n\
+                   deposit, 2, 3, 2.0\n\
+                   dispute, 2, 3\n";
+        let mut service = Service::new();
+
+        let errors = service.apply_async(csv.as_bytes()).await.unwrap();
+
+        assert_eq!(1, errors.len());
+        assert!(matches!(
+            errors[0],
+            (1, TransactionError::UnsufficientFunds(2))
+        ));
+        assert_eq!(Amount(5.), service.accounts[&1].available());
+        assert_eq!(Amount(2.), service.accounts[&2].held());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_deposit_without_amount_should_be_rejected() {
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 5.0\n\
+                   deposit, 1, 2\n";
+        let mut service = Service::new();
+
+        let result = service.apply_async(csv.as_bytes()).await;
+
+        assert!(matches!(
+            result,
+            Err(RecordError::Malformed {
+                line: Some(3),
+                found: 3,
+                ..
+            })
+        ));
+        assert_eq!(Amount(5.), service.accounts[&1].available());
+    }
+
+    #[test]
+    fn replayed_transactions_should_be_guarded() {
+        let txs = [
+            deposit(1, 1, 5.),
+            withdrawal(1, 2, 1.),
+            deposit(1, 1, 5.),
+            dispute(1, 2),
+            deposit(2, 1, 3.),
+        ];
+
+        let mut skipping = Service::builder().replay_guard(ReplayGuard::Skip).build();
+        assert!(skipping.apply_slice(&txs).is_empty());
+        assert_eq!(Amount(1.), skipping.accounts[&1].held());
+        assert_eq!(Amount(4.), skipping.accounts[&1].available());
+        assert_eq!(4, skipping.stats().processed());
+
+        let mut rejecting = Service::builder().replay_guard(ReplayGuard::Reject).build();
+        let errors = rejecting.apply_slice(&txs);
+        assert_eq!(1, errors.len());
+        assert!(matches!(errors[0], (2, TransactionError::OutOfOrder(1))));
+    }
+
+    #[test]
+    fn cloned_service_should_not_affect_original() {
+        let mut service = Service::new();
+        service.apply(&deposit(1, 1, 5.)).unwrap();
+
+        let mut what_if = service.clone();
+        what_if.apply(&withdrawal(1, 2, 2.)).unwrap();
+        what_if.apply(&deposit(2, 3, 1.)).unwrap();
+        assert_eq!(Amount(3.), what_if.account(1).unwrap().available());
+
+        assert_eq!(Amount(5.), service.account(1).unwrap().available());
+        assert!(service.account(2).is_none());
+        assert_eq!(1, service.stats().processed());
+        assert!(service.apply(&withdrawal(1, 2, 5.)).is_ok());
+    }
+
+    #[test]
+    fn ledger_should_match_accounts_after_mixed_workload() {
+        let txs = [
+            deposit(1, 1, 5.),
+            deposit(1, 2, 2.5),
+            withdrawal(1, 3, 1.25),
+            deposit(2, 4, 3.),
+            withdrawal(2, 5, 10.),
+            dispute(1, 3),
+            resolve(1, 3),
+            dispute(1, 3),
+            chargeback(1, 3),
+            dispute(2, 4),
+            chargeback(2, 4),
+            deposit(3, 6, 2.),
+            withdrawal(3, 7, 1.),
+            dispute(3, 7),
+        ];
+
+        let mut service = Service::new();
+        assert_eq!(1, service.apply_slice(&txs).len());
+        assert_eq!(Ok(()), service.check_ledger());
+        assert_eq!(Amount(12.5), service.ledger().deposited);
+        assert_eq!(Amount(1.), service.ledger().withdrawn);
+        assert_eq!(Amount(3.), service.ledger().charged_back);
+        assert_eq!(Amount(1.), service.ledger().disputed_withdrawals);
+
+        let mut routing = Service::builder().chargeback_destination(0).build();
+        routing.apply_slice(&txs);
+        assert_eq!(Ok(()), routing.check_ledger());
+        assert_eq!(Amount(0.), routing.ledger().charged_back);
+
+        let mut partial = Service::builder()
+            .policy(AccountPolicy {
+                partial_withdrawal: true,
+                ..AccountPolicy::default()
+            })
+            .build();
+        partial.apply_slice(&txs);
+        assert_eq!(Ok(()), partial.check_ledger());
+        // Withdrawal of client 2 filled with all the 3 deposited
+        assert_eq!(Amount(4.), partial.ledger().withdrawn);
+    }
+
+    #[test]
+    fn ledger_should_report_inconsistent_accounts() {
+        let mut service = Service::new();
+        service.apply(&deposit(1, 1, 5.)).unwrap();
+        // Funds created outside of transaction handling
+        service.accounts.get_mut(&1).unwrap().credit(Amount(0.5));
+
+        let discrepancy = service.check_ledger().unwrap_err();
+        assert_eq!(Amount(5.), discrepancy.expected);
+        assert_eq!(Amount(5.5), discrepancy.actual);
+        assert_eq!(Amount(0.5), discrepancy.difference());
+    }
+
+    #[test]
+    fn ledger_should_open_with_restored_totals() {
+        let mut service = Service::new();
+        service.apply(&deposit(1, 1, 5.)).unwrap();
+        let mut snapshot = Vec::new();
+        service.snapshot(&mut snapshot).unwrap();
+
+        let mut restored = Service::new();
+        restored.restore(&snapshot[..]).unwrap();
+        assert_eq!(Amount(5.), restored.ledger().opening);
+        assert_eq!(Ok(()), restored.check_ledger());
+    }
+
+    #[test]
+    fn services_of_disjoint_clients_should_merge() {
+        let mut first = Service::new();
+        first.apply_slice(&[deposit(1, 1, 5.), withdrawal(1, 2, 10.)]);
+        let mut second = Service::new();
+        second.apply_slice(&[deposit(2, 3, 2.), dispute(2, 3)]);
+
+        first.merge(second).unwrap();
+
+        assert_eq!(Amount(5.), first.account(1).unwrap().available());
+        assert_eq!(Amount(2.), first.account(2).unwrap().held());
+        assert_eq!(4, first.stats().processed());
+        assert_eq!(Amount(7.), first.ledger().deposited);
+        assert_eq!(Ok(()), first.check_ledger());
+        // History of merged accounts comes along
+        first.apply(&resolve(2, 3)).unwrap();
+        assert_eq!(Amount(2.), first.account(2).unwrap().available());
+    }
+
+    #[test]
+    fn merging_services_with_same_client_should_fail() {
+        let mut first = Service::new();
+        first.apply(&deposit(1, 1, 1.)).unwrap();
+        let mut second = Service::new();
+        second.apply(&deposit(2, 2, 1.)).unwrap();
+        second.apply(&deposit(1, 3, 1.)).unwrap();
+
+        assert!(matches!(
+            first.merge(second),
+            Err(MergeError::ClientCollision(1))
+        ));
+        assert!(first.account(2).is_none());
+        assert_eq!(Amount(1.), first.account(1).unwrap().total());
+    }
+
+    #[test]
+    fn merged_audit_log_should_be_numbered_on() {
+        let mut first = Service::builder().audit_log().build();
+        first.apply_slice(&[deposit(1, 1, 1.), deposit(1, 2, 1.)]);
+        let mut second = Service::builder().audit_log().build();
+        second.apply_slice(&[deposit(2, 3, 1.), dispute(2, 3)]);
+
+        first.merge(second).unwrap();
+
+        let entries: Vec<_> = first
+            .audit_log()
+            .iter()
+            .map(|entry| (entry.seq, entry.transaction.tx))
+            .collect();
+        assert_eq!(vec![(0, 1), (1, 2), (2, 3), (3, 3)], entries);
+    }
+
+    #[test]
+    fn shards_sharing_chargeback_destination_should_merge() {
+        let shard = |client, tx| {
+            let mut service = Service::builder().chargeback_destination(9).build();
+            service.apply_slice(&[
+                deposit(client, tx, 2.),
+                dispute(client, tx),
+                chargeback(client, tx),
+            ]);
+            service
+        };
+        let mut first = shard(1, 1);
+
+        first.merge(shard(2, 2)).unwrap();
+
+        assert_eq!(Amount(4.), first.account(9).unwrap().available());
+        assert!(first.account(2).unwrap().locked());
+        assert_eq!(Ok(()), first.check_ledger());
+
+        // Destination with transactions of its own is a client like any other
+        let mut active = shard(3, 3);
+        active.apply(&deposit(9, 4, 1.)).unwrap();
+        assert!(matches!(
+            first.merge(active),
+            Err(MergeError::ClientCollision(9))
+        ));
+    }
+
+    #[test]
+    fn observer_should_see_every_outcome() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        #[derive(Default)]
+        struct Recording {
+            applied: Vec<TransactionId>,
+            rejected: Vec<(TransactionId, &'static str)>,
+        }
+        struct Recorder(Rc<RefCell<Recording>>);
+        impl Observer for Recorder {
+            fn applied(&mut self, tx: &Transaction, _account: &Account) {
+                self.0.borrow_mut().applied.push(tx.tx);
+            }
+            fn rejected(&mut self, tx: &Transaction, error: &TransactionError) {
+                self.0.borrow_mut().rejected.push((tx.tx, error.name()));
+            }
+        }
+
+        let recording = Rc::new(RefCell::new(Recording::default()));
+        let mut service = Service::builder()
+            .observer(Recorder(recording.clone()))
+            .build();
+        service.apply_slice(&[
+            deposit(1, 1, 5.),
+            withdrawal(1, 2, 7.),
+            dispute(1, 1),
+            resolve(1, 3),
+        ]);
+
+        let recording = recording.borrow();
+        assert_eq!(vec![1, 1], recording.applied);
+        assert_eq!(
+            vec![(2, "UnsufficientFunds"), (3, "NotFound")],
+            recording.rejected
+        );
+    }
+
+    #[test]
+    fn apply_all_should_follow_error_policy() {
+        let txs = [
+            deposit(1, 1, 5.),
+            withdrawal(1, 2, 7.),
+            deposit(1, 3, 1.),
+            dispute(1, 4),
+            deposit(2, 5, 2.),
+        ];
+
+        let mut continuing = Service::new();
+        let errors = continuing
+            .apply_all(&txs, ErrorPolicy::ContinueOnError)
+            .unwrap();
+        assert_eq!(2, errors.len());
+        assert!(matches!(
+            errors[..],
+            [
+                (1, TransactionError::UnsufficientFunds(2)),
+                (3, TransactionError::NotFound(4))
+            ]
+        ));
+        assert_eq!(Amount(6.), continuing.account(1).unwrap().available());
+        assert_eq!(Amount(2.), continuing.account(2).unwrap().available());
+
+        let mut stopping = Service::new();
+        assert!(matches!(
+            stopping.apply_all(&txs, ErrorPolicy::StopOnFirstError),
+            Err((1, TransactionError::UnsufficientFunds(2)))
+        ));
+        assert_eq!(Amount(5.), stopping.account(1).unwrap().available());
+        assert!(stopping.account(2).is_none());
+
+        assert!(Service::new()
+            .apply_all(&txs[..1], ErrorPolicy::StopOnFirstError)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn csv_and_in_memory_sources_should_apply_the_same() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,5.0\n\
+                     withdrawal,1,2,7.0\n\
+                     dispute,1,1,\n";
+        let txs = vec![deposit(1, 1, 5.), withdrawal(1, 2, 7.), dispute(1, 1)];
+
+        let mut from_csv = Service::new();
+        let csv_errors = from_csv
+            .apply_source(crate::source::CsvSource::new(input.as_bytes()))
+            .unwrap();
+        let mut from_vec = Service::new();
+        let vec_errors = from_vec.apply_source(txs.into_iter()).unwrap();
+
+        assert!(matches!(
+            csv_errors[..],
+            [(1, TransactionError::UnsufficientFunds(2))]
+        ));
+        assert!(matches!(
+            vec_errors[..],
+            [(1, TransactionError::UnsufficientFunds(2))]
+        ));
+        for service in [&from_csv, &from_vec] {
+            let account = service.account(1).unwrap();
+            assert_eq!(Amount(0.), account.available());
+            assert_eq!(Amount(5.), account.held());
+        }
+    }
+
+    #[test]
+    fn voided_deposit_should_leave_ledger_balanced() {
+        let mut service = Service::new();
+        service.apply_slice(&[deposit(1, 1, 5.), deposit(1, 2, 2.)]);
+
+        service.void_deposit(1, 1).unwrap();
+
+        assert_eq!(Amount(2.), service.account(1).unwrap().total());
+        assert_eq!(Amount(2.), service.ledger().deposited);
+        service.check_ledger().unwrap();
+        assert!(matches!(
+            service.void_deposit(2, 2),
+            Err(TransactionError::NotFound(2))
+        ));
+    }
+
+    #[test]
+    fn state_digest_should_only_depend_on_account_states() {
+        let run = |last_deposit| {
+            let mut service = Service::new();
+            service.apply_slice(&[
+                deposit(3, 1, 1.5),
+                deposit(1, 2, 2.),
+                deposit(2, 3, 4.),
+                dispute(2, 3),
+                chargeback(2, 3),
+                deposit(1, 4, last_deposit),
+            ]);
+            service
+        };
+
+        assert_eq!(run(1.).state_digest(), run(1.).state_digest());
+        assert_eq!(run(1.).state_digest(), run(1.00001).state_digest());
+        assert_ne!(run(1.).state_digest(), run(1.0001).state_digest());
+        assert_ne!(run(1.).state_digest(), Service::new().state_digest());
+    }
+
+    #[test]
+    fn transactions_of_unknown_clients_should_follow_policy() {
+        let txs = [
+            withdrawal(1, 1, 1.),
+            dispute(2, 1),
+            deposit(3, 2, 2.),
+            withdrawal(3, 3, 1.),
+        ];
+
+        let mut creating = Service::new();
+        let errors = creating.apply_slice(&txs);
+        assert!(matches!(
+            errors[..],
+            [
+                (0, TransactionError::UnsufficientFunds(1)),
+                (1, TransactionError::NotFound(1))
+            ]
+        ));
+        assert_eq!(2, creating.accounts().count());
+
+        let mut rejecting = Service::builder()
+            .unknown_clients(UnknownClientPolicy::Reject)
+            .build();
+        let errors = rejecting.apply_slice(&txs);
+        assert!(matches!(
+            errors[..],
+            [
+                (0, TransactionError::UnknownClient(1)),
+                (1, TransactionError::UnknownClient(2))
+            ]
+        ));
+        assert_eq!(1, rejecting.accounts().count());
+        assert_eq!(Amount(1.), rejecting.account(3).unwrap().available());
+    }
+
+    #[test]
+    fn stream_should_be_checkpointed_periodically() {
+        let txs = (1..=5).map(|i| deposit(1, i, 1.));
+        let mut service = Service::new();
+        let mut checkpoints = Vec::new();
+
+        service.process_stream(txs, 2, |service| {
+            let mut snapshot = Vec::new();
+            service.snapshot(&mut snapshot).unwrap();
+            checkpoints.push((service.account(1).unwrap().total(), snapshot));
+        });
+
+        let totals: Vec<_> = checkpoints.iter().map(|(total, _)| *total).collect();
+        assert_eq!(vec![Amount(2.), Amount(4.), Amount(5.)], totals);
+        let mut restored = Service::new();
+        restored.restore(&checkpoints[1].1[..]).unwrap();
+        assert_eq!(Amount(4.), restored.account(1).unwrap().total());
+        assert_eq!(5, service.stats().processed());
+    }
+
+    #[test]
+    fn transactions_with_seen_idempotency_key_should_be_skipped() {
+        let keyed = |r#type, client, id, amount, key: &str| Transaction {
+            idempotency_key: Some(key.to_string()),
+            ..transaction(r#type, client, id, amount)
+        };
+        let mut service = Service::new();
+
+        let errors = service.apply_slice(&[
+            keyed(TransactionType::Deposit, 1, 1, 5., "a"),
+            keyed(TransactionType::Deposit, 1, 2, 5., "a"),
+            keyed(TransactionType::Deposit, 2, 3, 1., "a"),
+            keyed(TransactionType::Withdrawal, 1, 4, 9., "b"),
+            keyed(TransactionType::Withdrawal, 1, 5, 2., "b"),
+            keyed(TransactionType::Withdrawal, 1, 6, 2., "b"),
+            deposit(1, 7, 1.),
+        ]);
+
+        assert!(matches!(
+            errors[..],
+            [(3, TransactionError::UnsufficientFunds(4))]
+        ));
+        assert_eq!(Amount(4.), service.account(1).unwrap().total());
+        assert_eq!(Amount(1.), service.account(2).unwrap().total());
+        assert!(!service.account(1).unwrap().has_tx(2));
+        assert!(!service.account(1).unwrap().has_tx(6));
+    }
+
+    #[test]
+    fn lone_dispute_should_not_open_account() {
+        let mut service = Service::new();
+
+        let errors = service.apply_slice(&[dispute(1, 1), resolve(2, 1), chargeback(3, 1)]);
+
+        assert!(matches!(
+            errors[..],
+            [
+                (0, TransactionError::NotFound(1)),
+                (1, TransactionError::NotFound(1)),
+                (2, TransactionError::NotFound(1))
+            ]
+        ));
+        assert_eq!(0, service.accounts().count());
+        let mut output = Vec::new();
+        service.write_csv(&mut output).unwrap();
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn transactions_should_be_applied_in_order_of_timestamps() {
+        let at = |r#type, id, amount, timestamp| Transaction {
+            timestamp: Some(timestamp),
+            ..transaction(r#type, 1, id, amount)
+        };
+        let txs = vec![
+            at(TransactionType::Dispute, 1, 0., 200),
+            at(TransactionType::Withdrawal, 2, 9., 300),
+            at(TransactionType::Deposit, 1, 5., 100),
+            at(TransactionType::Deposit, 3, 1., 200),
+        ];
+
+        let mut in_file_order = Service::new();
+        assert!(matches!(
+            in_file_order.apply_slice(&txs)[..],
+            [
+                (0, TransactionError::NotFound(1)),
+                (1, TransactionError::UnsufficientFunds(2))
+            ]
+        ));
+
+        let mut service = Service::new();
+        let errors = service.apply_sorted_by_time(txs);
+
+        assert!(matches!(
+            errors[..],
+            [(1, TransactionError::UnsufficientFunds(2))]
+        ));
+        let account = service.account(1).unwrap();
+        assert_eq!(Amount(5.), account.held());
+        assert_eq!(Amount(1.), account.available());
+    }
+
+    #[test]
+    fn apply_with_outcome_should_tell_skipped_and_locking_transactions() {
+        let mut service = Service::builder().replay_guard(ReplayGuard::Skip).build();
+        let keyed = Transaction {
+            idempotency_key: Some("a".to_string()),
+            ..deposit(1, 3, 1.)
+        };
+
+        let outcomes = [
+            service.apply_with_outcome(&deposit(1, 2, 5.)),
+            service.apply_with_outcome(&deposit(1, 1, 5.)),
+            service.apply_with_outcome(&keyed),
+            service.apply_with_outcome(&Transaction { tx: 4, ..keyed }),
+            service.apply_with_outcome(&dispute(1, 2)),
+            service.apply_with_outcome(&chargeback(1, 2)),
+        ];
+
+        assert!(matches!(
+            outcomes,
+            [
+                Ok(TransactionOutcome::Applied),
+                Ok(TransactionOutcome::Ignored),
+                Ok(TransactionOutcome::Applied),
+                Ok(TransactionOutcome::Ignored),
+                Ok(TransactionOutcome::Applied),
+                Ok(TransactionOutcome::AppliedLockedAccount),
+            ]
+        ));
+        assert_eq!(Amount(1.), service.account(1).unwrap().total());
+    }
+
+    #[test]
+    fn replaced_transactions_should_keep_ledger_balanced() {
+        let mut service = Service::builder()
+            .policy(AccountPolicy {
+                on_duplicate: DuplicatePolicy::ReplaceIfNotDisputed,
+                ..AccountPolicy::default()
+            })
+            .build();
+
+        let errors = service.apply_slice(&[
+            deposit(1, 1, 5.),
+            withdrawal(1, 2, 1.),
+            deposit(1, 1, 7.),
+            withdrawal(1, 2, 3.),
+        ]);
+
+        assert!(errors.is_empty());
+        assert_eq!(Amount(4.), service.account(1).unwrap().total());
+        assert_eq!(Ok(()), service.check_ledger());
+    }
+
+    #[test]
+    fn hold_hook_should_adjust_available_funds() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let held = Rc::new(RefCell::new(Vec::new()));
+        let mut service = Service::new();
+        let seen = held.clone();
+        service.on_hold(Box::new(move |tx, amount| {
+            seen.borrow_mut().push((tx, amount));
+            Amount(-0.01 * amount.0)
+        }));
+
+        let errors = service.apply_slice(&[
+            deposit(1, 1, 100.),
+            deposit(1, 2, 10.),
+            dispute(1, 1),
+            dispute(1, 1),
+            resolve(1, 1),
+        ]);
+
+        assert_eq!(1, errors.len());
+        assert_eq!(vec![(1, Amount(100.))], *held.borrow());
+        let account = service.account(1).unwrap();
+        assert_eq!(Amount(109.), account.available());
+        assert_eq!(Amount(109.), account.total());
+        assert_eq!(Amount(-1.), service.ledger().hold_adjustments);
+        assert_eq!(Ok(()), service.check_ledger());
+
+        let mut default = Service::new();
+        default.apply_slice(&[deposit(1, 1, 100.), dispute(1, 1)]);
+        assert_eq!(Amount(100.), default.account(1).unwrap().total());
+    }
+
+    #[test]
+    fn apply_and_get_should_return_changed_account() {
+        let mut service = Service::new();
+
+        let keyed = Transaction {
+            idempotency_key: Some("k".to_string()),
+            ..deposit(1, 1, 5.)
+        };
+        let account = service.apply_and_get(&keyed).unwrap();
+        assert_eq!((1, Amount(5.)), (account.id(), account.available()));
+
+        let account = service.apply_and_get(&dispute(1, 1)).unwrap();
+        assert_eq!(Amount(0.), account.available());
+        assert_eq!(Amount(5.), account.held());
+
+        assert!(matches!(
+            service.apply_and_get(&withdrawal(1, 2, 1.)),
+            Err(TransactionError::UnsufficientFunds(2))
+        ));
+        // Skipped transaction is a success, the account is returned as it was
+        let account = service
+            .apply_and_get(&Transaction { tx: 3, ..keyed })
+            .unwrap();
+        assert_eq!(Amount(5.), account.held());
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn spans_and_events_should_be_emitted_for_deposit_and_dispute() {
+        use std::fmt::{Debug, Write};
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::{Arc, Mutex};
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        #[derive(Default)]
+        struct Fields(String);
+
+        impl Visit for Fields {
+            fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+                write!(self.0, " {}={:?}", field.name(), value).unwrap();
+            }
+        }
+
+        #[derive(Default)]
+        struct Recorder {
+            lines: Arc<Mutex<Vec<String>>>,
+            next_id: AtomicU64,
+        }
+
+        impl Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut fields = Fields::default();
+                span.record(&mut fields);
+                let line = format!("span {}{}", span.metadata().name(), fields.0);
+                self.lines.lock().unwrap().push(line);
+                Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) + 1)
+            }
+
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+            fn event(&self, event: &Event<'_>) {
+                let mut fields = Fields::default();
+                event.record(&mut fields);
+                let line = format!("event{}", fields.0);
+                self.lines.lock().unwrap().push(line);
+            }
+
+            fn enter(&self, _: &Id) {}
+
+            fn exit(&self, _: &Id) {}
+        }
+
+        let recorder = Recorder::default();
+        let lines = recorder.lines.clone();
+        let mut service = Service::new();
+        tracing::subscriber::with_default(recorder, || {
+            service.apply(&deposit(1, 1, 5.)).unwrap();
+            service.apply(&dispute(1, 1)).unwrap();
+        });
+
+        let lines = lines.lock().unwrap();
+        assert_eq!(
+            vec![
+                "span apply client=1 tx=1 kind=Deposit",
+                "span apply client=1 tx=1 kind=Dispute",
+                "event message=Dispute state changed client=1 tx=1 transition=Dispute",
+            ],
+            *lines
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use thiserror::Error;
+
+use crate::record::RecordError;
+use crate::transaction::Transaction;
+
+/// Possible errors of reading transactions from a `TransactionSource`
+#[derive(Error, Debug)]
+pub enum SourceError {
+    #[error("Failed to read csv input: {0}")]
+    Csv(#[from] csv::Error),
+    #[error(transparent)]
+    Record(#[from] RecordError),
+}
+
+/// Source of transactions, yielding them one by one in the order they are to be applied
+///
+/// Lets `Service::apply_source` consume transactions regardless of their format.
+pub trait TransactionSource {
+    /// Get the next transaction, `None` once the source is exhausted
+    fn next_transaction(&mut self) -> Option<Result<Transaction, SourceError>>;
+}
+
+/// Transactions read as csv with a header from a reader, as they arrive
+///
+/// Records are converted like the ones of csv input files, with
+/// `TryFrom<csv::StringRecord> for Transaction`.
+pub struct CsvSource<R> {
+    reader: csv::Reader<R>,
+    record: csv::StringRecord,
+}
+
+impl<R: std::io::Read> CsvSource<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .flexible(true)
+                .from_reader(reader),
+            record: csv::StringRecord::new(),
+        }
+    }
+}
+
+impl<R: std::io::Read> TransactionSource for CsvSource<R> {
+    fn next_transaction(&mut self) -> Option<Result<Transaction, SourceError>> {
+        match self.reader.read_record(&mut self.record) {
+            Ok(true) => (),
+            Ok(false) => return None,
+            Err(e) => return Some(Err(e.into())),
+        }
+        Some(Transaction::try_from(std::mem::take(&mut self.record)).map_err(SourceError::from))
+    }
+}
+
+/// Transactions held in memory, eg. parsed already by the caller
+impl TransactionSource for std::vec::IntoIter<Transaction> {
+    fn next_transaction(&mut self) -> Option<Result<Transaction, SourceError>> {
+        self.next().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{Amount, TransactionType};
+
+    fn read_all(source: &mut impl TransactionSource) -> Vec<Result<Transaction, SourceError>> {
+        std::iter::from_fn(|| source.next_transaction()).collect()
+    }
+
+    #[test]
+    fn csv_source_should_yield_transactions_in_order() {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 5.12345\n\
+                     dispute, 1, 1,\n\
+                     resolve, 1, 1\n";
+
+        let txs: Vec<_> = read_all(&mut CsvSource::new(input.as_bytes()))
+            .into_iter()
+            .map(|tx| {
+                let tx = tx.unwrap();
+                (tx.r#type, tx.client, tx.tx, tx.amount)
+            })
+            .collect();
+
+        assert_eq!(
+            vec![
+                (TransactionType::Deposit, 1, 1, Amount(5.1234)),
+                (TransactionType::Dispute, 1, 1, Amount(0.)),
+                (TransactionType::Resolve, 1, 1, Amount(0.)),
+            ],
+            txs
+        );
+    }
+
+    #[test]
+    fn csv_source_should_report_invalid_records() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,\n\
+                     deposit,1,2,1.0\n";
+        let txs = read_all(&mut CsvSource::new(input.as_bytes()));
+
+        assert_eq!(2, txs.len());
+        assert!(matches!(
+            txs[0],
+            Err(SourceError::Record(RecordError::EmptyAmount { .. }))
+        ));
+        assert!(txs[1].is_ok());
+    }
+
+    #[test]
+    fn csv_source_should_read_records_like_csv_input() {
+        let input = "type,client,tx,amount\n\
+                     Deposit,1,1,2.0\n\
+                     withdrawal,1,2,-5\n\
+                     deposit,1,3,NaN\n\
+                     deposit,70000,4,1.0\n";
+        let txs = read_all(&mut CsvSource::new(input.as_bytes()));
+
+        assert_eq!(TransactionType::Deposit, txs[0].as_ref().unwrap().r#type);
+        assert!(matches!(
+            &txs[1],
+            Err(SourceError::Record(RecordError::BadAmount { amount, .. })) if amount == "-5"
+        ));
+        assert!(matches!(
+            txs[2],
+            Err(SourceError::Record(RecordError::BadAmount { .. }))
+        ));
+        assert!(matches!(
+            txs[3],
+            Err(SourceError::Record(RecordError::ClientIdOutOfRange { .. }))
+        ));
+    }
+}
@@ -0,0 +1,96 @@
+//! Storage of accounts a service operates on
+
+use std::collections::HashMap;
+use thiserror::Error;
+
+use crate::account::Account;
+use crate::transaction::{ClientId, TransactionId};
+
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+/// Possible errors of persisting accounts
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("Failed to (de)serialize account: {0}")]
+    Json(#[from] serde_json::Error),
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite failure: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+/// Result type used when persisting accounts
+pub type StoreResult<T> = Result<T, StoreError>;
+
+/// Storage of accounts, keyed by ids of their clients
+///
+/// Accounts are handed out by reference and modified in place. Store is told about
+/// every modification with `persist`, so that it can write the changes through.
+pub trait AccountStore {
+    /// Get account of `client_id`
+    fn get(&self, client_id: ClientId) -> Option<&Account>;
+
+    /// Get account of `client_id` for modification, `persist` should follow
+    fn get_mut(&mut self, client_id: ClientId) -> Option<&mut Account>;
+
+    /// Get account of `client_id` for modification, creating it with `create` if needed
+    fn get_or_insert_with(
+        &mut self,
+        client_id: ClientId,
+        create: impl FnOnce() -> Account,
+    ) -> &mut Account;
+
+    /// Get all the stored accounts, in no particular order
+    fn accounts(&self) -> impl Iterator<Item = &Account>;
+
+    /// Replace all the stored accounts with given ones
+    fn replace_all(&mut self, accounts: Vec<Account>) -> StoreResult<()>;
+
+    /// Store given account along with its history, replacing an account of the same client
+    fn insert(&mut self, account: Account) -> StoreResult<()>;
+
+    /// Persist modifications of account of `client_id`
+    ///
+    /// `tx` is the transaction from the history of the account that may have changed
+    fn persist(&mut self, client_id: ClientId, tx: Option<TransactionId>) -> StoreResult<()>;
+}
+
+/// Accounts kept only in memory, the default store
+impl AccountStore for HashMap<ClientId, Account> {
+    fn get(&self, client_id: ClientId) -> Option<&Account> {
+        HashMap::get(self, &client_id)
+    }
+
+    fn get_mut(&mut self, client_id: ClientId) -> Option<&mut Account> {
+        HashMap::get_mut(self, &client_id)
+    }
+
+    fn get_or_insert_with(
+        &mut self,
+        client_id: ClientId,
+        create: impl FnOnce() -> Account,
+    ) -> &mut Account {
+        self.entry(client_id).or_insert_with(create)
+    }
+
+    fn accounts(&self) -> impl Iterator<Item = &Account> {
+        self.values()
+    }
+
+    fn replace_all(&mut self, accounts: Vec<Account>) -> StoreResult<()> {
+        *self = accounts
+            .into_iter()
+            .map(|account| (account.id(), account))
+            .collect();
+        Ok(())
+    }
+
+    fn insert(&mut self, account: Account) -> StoreResult<()> {
+        HashMap::insert(self, account.id(), account);
+        Ok(())
+    }
+
+    fn persist(&mut self, _client_id: ClientId, _tx: Option<TransactionId>) -> StoreResult<()> {
+        Ok(())
+    }
+}
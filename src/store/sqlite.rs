@@ -0,0 +1,238 @@
+//! Accounts persisted to an SQLite database, available with `sqlite` feature
+
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+
+use super::{AccountStore, StoreResult};
+use crate::account::{Account, DisputableTransaction};
+use crate::transaction::{ClientId, TransactionId};
+
+/// Accounts kept in memory and written through to an SQLite database
+///
+/// Each account is stored as a row of `accounts` table, while its history is stored
+/// in `transactions` table, one row per transaction. Both are stored as json.
+#[derive(Debug)]
+pub struct SqliteStore {
+    connection: Connection,
+    accounts: HashMap<ClientId, Account>,
+}
+
+impl SqliteStore {
+    /// Open database at `path`, creating it if needed, and load all the accounts from it
+    pub fn open(path: impl AsRef<std::path::Path>) -> StoreResult<Self> {
+        Self::load(Connection::open(path)?)
+    }
+
+    /// Open a fresh database living only in memory
+    pub fn open_in_memory() -> StoreResult<Self> {
+        Self::load(Connection::open_in_memory()?)
+    }
+
+    fn load(connection: Connection) -> StoreResult<Self> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS accounts (
+                 client INTEGER PRIMARY KEY,
+                 account TEXT NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS transactions (
+                 client INTEGER NOT NULL,
+                 tx INTEGER NOT NULL,
+                 entry TEXT NOT NULL,
+                 PRIMARY KEY (client, tx)
+             );",
+        )?;
+        let accounts = read_accounts(&connection)?;
+        Ok(Self {
+            connection,
+            accounts,
+        })
+    }
+}
+
+/// Read all the accounts along with their history
+fn read_accounts(connection: &Connection) -> StoreResult<HashMap<ClientId, Account>> {
+    let mut accounts = HashMap::new();
+    let mut statement = connection.prepare("SELECT client, account FROM accounts")?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let account: Account = serde_json::from_str(&row.get::<_, String>(1)?)?;
+        accounts.insert(row.get(0)?, account);
+    }
+    let mut statement = connection.prepare("SELECT client, tx, entry FROM transactions")?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let entry: DisputableTransaction = serde_json::from_str(&row.get::<_, String>(2)?)?;
+        if let Some(account) = accounts.get_mut(&row.get(0)?) {
+            account.restore_history_entry(row.get(1)?, entry);
+        }
+    }
+    Ok(accounts)
+}
+
+/// Write `account` along with a single entry of its history, if given
+fn write_account(
+    connection: &Connection,
+    account: &mut Account,
+    tx: Option<TransactionId>,
+) -> StoreResult<()> {
+    connection.execute(
+        "INSERT OR REPLACE INTO accounts (client, account) VALUES (?1, ?2)",
+        params![account.id(), account.json_without_history()?],
+    )?;
+    if let Some(entry) = tx.and_then(|tx| account.history_entry(tx).map(|entry| (tx, entry))) {
+        write_entry(connection, account.id(), entry)?;
+    }
+    Ok(())
+}
+
+fn write_entry(
+    connection: &Connection,
+    client_id: ClientId,
+    (tx, entry): (TransactionId, &DisputableTransaction),
+) -> StoreResult<()> {
+    connection.execute(
+        "INSERT OR REPLACE INTO transactions (client, tx, entry) VALUES (?1, ?2, ?3)",
+        params![client_id, tx, serde_json::to_string(entry)?],
+    )?;
+    Ok(())
+}
+
+impl AccountStore for SqliteStore {
+    fn get(&self, client_id: ClientId) -> Option<&Account> {
+        self.accounts.get(&client_id)
+    }
+
+    fn get_mut(&mut self, client_id: ClientId) -> Option<&mut Account> {
+        self.accounts.get_mut(&client_id)
+    }
+
+    fn get_or_insert_with(
+        &mut self,
+        client_id: ClientId,
+        create: impl FnOnce() -> Account,
+    ) -> &mut Account {
+        self.accounts.entry(client_id).or_insert_with(create)
+    }
+
+    fn accounts(&self) -> impl Iterator<Item = &Account> {
+        self.accounts.values()
+    }
+
+    fn replace_all(&mut self, accounts: Vec<Account>) -> StoreResult<()> {
+        let transaction = self.connection.transaction()?;
+        transaction.execute_batch("DELETE FROM accounts; DELETE FROM transactions;")?;
+        let mut replaced = HashMap::new();
+        for mut account in accounts {
+            write_account(&transaction, &mut account, None)?;
+            for (&tx, entry) in account.history_entries() {
+                write_entry(&transaction, account.id(), (tx, entry))?;
+            }
+            replaced.insert(account.id(), account);
+        }
+        transaction.commit()?;
+        self.accounts = replaced;
+        Ok(())
+    }
+
+    fn insert(&mut self, mut account: Account) -> StoreResult<()> {
+        let transaction = self.connection.transaction()?;
+        transaction.execute(
+            "DELETE FROM transactions WHERE client = ?1",
+            params![account.id()],
+        )?;
+        write_account(&transaction, &mut account, None)?;
+        for (&tx, entry) in account.history_entries() {
+            write_entry(&transaction, account.id(), (tx, entry))?;
+        }
+        transaction.commit()?;
+        self.accounts.insert(account.id(), account);
+        Ok(())
+    }
+
+    fn persist(&mut self, client_id: ClientId, tx: Option<TransactionId>) -> StoreResult<()> {
+        match self.accounts.get_mut(&client_id) {
+            Some(account) => {
+                let transaction = self.connection.transaction()?;
+                write_account(&transaction, account, tx)?;
+                transaction.commit()?;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{chargeback, deposit, dispute, resolve, withdrawal};
+    use crate::transaction::{Amount, Transaction, TransactionType};
+    use crate::Service;
+
+    fn transactions() -> Vec<Transaction> {
+        vec![
+            deposit(1, 1, 5.),
+            deposit(2, 2, 3.5),
+            withdrawal(1, 3, 1.25),
+            withdrawal(2, 4, 7.),
+            dispute(1, 3),
+            resolve(1, 3),
+            dispute(2, 2),
+            chargeback(2, 2),
+            deposit(2, 5, 1.),
+            dispute(1, 2),
+        ]
+    }
+
+    fn balances<S: AccountStore>(service: &Service<S>) -> Vec<(ClientId, Amount, Amount, bool)> {
+        let mut balances: Vec<_> = service
+            .accounts()
+            .map(|a| (a.id(), a.available(), a.held(), a.locked()))
+            .collect();
+        balances.sort_by_key(|&(id, ..)| id);
+        balances
+    }
+
+    #[test]
+    fn sqlite_store_should_give_same_balances_as_memory() {
+        let mut in_memory = Service::new();
+        let mut sqlite =
+            Service::builder().build_with_store(SqliteStore::open_in_memory().unwrap());
+
+        let in_memory_errors = in_memory.apply_slice(&transactions());
+        let sqlite_errors = sqlite.apply_slice(&transactions());
+
+        let names = |errors: Vec<(usize, crate::account::TransactionError)>| -> Vec<_> {
+            errors.into_iter().map(|(i, e)| (i, e.name())).collect()
+        };
+        assert_eq!(names(in_memory_errors), names(sqlite_errors));
+        assert_eq!(balances(&in_memory), balances(&sqlite));
+    }
+
+    #[test]
+    fn reopened_sqlite_store_should_dispute_earlier_transactions() {
+        let path =
+            std::env::temp_dir().join(format!("transactions-engine-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut service = Service::builder().build_with_store(SqliteStore::open(&path).unwrap());
+        service.apply_slice(&transactions()[..4]);
+        let expected = balances(&service);
+        drop(service);
+
+        let mut service = Service::builder().build_with_store(SqliteStore::open(&path).unwrap());
+        assert_eq!(expected, balances(&service));
+        service
+            .apply(&Transaction {
+                r#type: TransactionType::Dispute,
+                client: 1,
+                tx: 3,
+                amount: Amount(0.),
+                idempotency_key: None,
+                timestamp: None,
+            })
+            .unwrap();
+        assert_eq!(Amount(1.25), service.account(1).unwrap().held());
+        drop(service);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,47 @@
+//! Helpers building transactions for tests, not meant for processing real input
+//!
+//! Available with the `testutil` feature, amounts are given as plain floats.
+
+use crate::transaction::{Amount, ClientId, Transaction, TransactionId, TransactionType};
+
+/// Build a transaction of any type
+pub fn transaction(
+    r#type: TransactionType,
+    client: ClientId,
+    tx: TransactionId,
+    amount: f64,
+) -> Transaction {
+    Transaction {
+        r#type,
+        client,
+        tx,
+        amount: Amount(amount),
+        idempotency_key: None,
+        timestamp: None,
+    }
+}
+
+/// Build a deposit of `amount`
+pub fn deposit(client: ClientId, tx: TransactionId, amount: f64) -> Transaction {
+    transaction(TransactionType::Deposit, client, tx, amount)
+}
+
+/// Build a withdrawal of `amount`
+pub fn withdrawal(client: ClientId, tx: TransactionId, amount: f64) -> Transaction {
+    transaction(TransactionType::Withdrawal, client, tx, amount)
+}
+
+/// Build a dispute of transaction `tx`
+pub fn dispute(client: ClientId, tx: TransactionId) -> Transaction {
+    transaction(TransactionType::Dispute, client, tx, 0.)
+}
+
+/// Build a resolve of transaction `tx`
+pub fn resolve(client: ClientId, tx: TransactionId) -> Transaction {
+    transaction(TransactionType::Resolve, client, tx, 0.)
+}
+
+/// Build a chargeback of transaction `tx`
+pub fn chargeback(client: ClientId, tx: TransactionId) -> Transaction {
+    transaction(TransactionType::Chargeback, client, tx, 0.)
+}
@@ -1,8 +1,50 @@
+use alloc::string::{String, ToString};
+
+use crate::math;
+
 pub type ClientId = u16;
 pub type TransactionId = u32;
 
+/// Default number of decimal places amounts are read and written with
+pub const DECIMAL_PLACES: i32 = 4;
+
+/// Number of decimal places of amounts, shared by all the threads of the program
+static PRECISION: core::sync::atomic::AtomicI32 =
+    core::sync::atomic::AtomicI32::new(DECIMAL_PLACES);
+
+// Tests of the crate set precision of their own thread only, so that they can run in parallel
+#[cfg(test)]
+std::thread_local! {
+    static TEST_PRECISION: core::cell::Cell<Option<i32>> = const { core::cell::Cell::new(None) };
+}
+
+/// Get number of decimal places amounts are currently read and written with
+pub fn precision() -> i32 {
+    #[cfg(test)]
+    if let Some(decimal_places) = TEST_PRECISION.with(core::cell::Cell::get) {
+        return decimal_places;
+    }
+    PRECISION.load(core::sync::atomic::Ordering::Relaxed)
+}
+
+/// Read and write amounts with given number of `decimal_places` from now on
+///
+/// Precision is a setting of the whole program, shared by all its threads, eg. workers
+/// of an async runtime, as serde gives no way of passing it to (de)serialization of
+/// an amount. It is read by comparing and hashing amounts as well, so it must be set once,
+/// before the first amount is created. Changing it later changes how existing amounts
+/// compare, hash, eg. as keys of a `HashMap`, and are written.
+pub fn set_precision(decimal_places: i32) {
+    #[cfg(test)]
+    TEST_PRECISION.with(|precision| precision.set(Some(decimal_places)));
+    #[cfg(not(test))]
+    PRECISION.store(decimal_places, core::sync::atomic::Ordering::Relaxed);
+}
+
 /// Possible types of transactions
-#[derive(Debug, Copy, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
     Deposit,
@@ -12,8 +54,46 @@ pub enum TransactionType {
     Chargeback,
 }
 
+impl TransactionType {
+    /// Get name of the transaction type, as it appears in input
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+        }
+    }
+}
+
+/// Error of parsing a name that is not one of known transaction types
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("Unknown transaction type `{0}`")]
+pub struct UnknownTransactionType(pub String);
+
+impl TryFrom<&str> for TransactionType {
+    type Error = UnknownTransactionType;
+
+    fn try_from(name: &str) -> Result<Self, Self::Error> {
+        match name {
+            "deposit" => Ok(TransactionType::Deposit),
+            "withdrawal" => Ok(TransactionType::Withdrawal),
+            "dispute" => Ok(TransactionType::Dispute),
+            "resolve" => Ok(TransactionType::Resolve),
+            "chargeback" => Ok(TransactionType::Chargeback),
+            _ => Err(UnknownTransactionType(name.to_string())),
+        }
+    }
+}
+
+/// Error of an arithmetic operation on amounts exceeding the bounds of f64
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("Amount overflowed")]
+pub struct AmountOverflow;
+
 /// Model of a single transaction
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
 pub struct Transaction {
     // Tbh I dislike having type as a field here instead of a Transaction being enclosed
     // in an enum, however csv-rs doesn't support reading internally tagged enums
@@ -21,51 +101,282 @@ pub struct Transaction {
     pub client: ClientId,
     pub tx: TransactionId,
     pub amount: Amount,
+    /// Key identifying the event across upstreams giving it different ids, see `Service::apply`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+    /// Time of the event in any unit, eg. unix seconds, see `Service::apply_sorted_by_time`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+}
+
+impl Transaction {
+    /// Check if the transaction moves funds on its own, that is if it is a deposit or withdrawal
+    ///
+    /// Disputes, resolves and chargebacks reference an earlier transaction instead,
+    /// their `amount` is a placeholder of 0 that is never used.
+    pub fn is_monetary(&self) -> bool {
+        matches!(
+            self.r#type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        )
+    }
+
+    /// Get amount of a monetary transaction, `None` for ones carrying a placeholder amount
+    pub fn monetary_amount(&self) -> Option<Amount> {
+        self.is_monetary().then_some(self.amount)
+    }
+
+    /// Create a transaction, meant for tests and fuzzing rather than processing input
+    #[cfg(feature = "fuzzing")]
+    pub fn new(
+        r#type: TransactionType,
+        client: ClientId,
+        tx: TransactionId,
+        amount: Amount,
+    ) -> Self {
+        Self {
+            r#type,
+            client,
+            tx,
+            amount,
+            idempotency_key: None,
+            timestamp: None,
+        }
+    }
 }
 
-/// A new-type over f64 that ensures reading/writing amounts with 4 dec digits precision
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, serde::Serialize, serde::Deserialize)]
+// Clients and ids are drawn from small ranges, so that arbitrary sequences of transactions
+// often reference each other. Amounts below 1000 keep float errors of balances well within
+// the tolerance of invariant checks.
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for Transaction {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let r#type = *u.choose(&[
+            TransactionType::Deposit,
+            TransactionType::Withdrawal,
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+        ])?;
+        let client = u.int_in_range(1..=4)?;
+        let tx = u.int_in_range(1..=32)?;
+        let amount = Amount::truncated(f64::from(u.int_in_range(0..=9_999_999u32)?) / 1e4);
+        Ok(Self::new(r#type, client, tx, amount))
+    }
+}
+
+/// A new-type over f64 that ensures reading/writing amounts with fixed dec digits precision
+///
+/// Amounts are compared after rounding them to current `precision`, so that float errors
+/// of arithmetic on balances don't affect the outcome, eg. `Amount(0.1 + 0.2)` equals
+/// `Amount(0.3)`. This is an interim measure until amounts become integer-backed.
+#[derive(Debug, Copy, Clone, Default, serde::Serialize, serde::Deserialize)]
 pub struct Amount(#[serde(with = "serde_amount")] pub f64);
 
+// Amounts are compared by bits of their rounded value, so that equality is total and agrees
+// with hashing. Apart from NaNs being equal to themselves, it gives the same results
+// as comparing rounded floats.
+impl PartialEq for Amount {
+    fn eq(&self, other: &Self) -> bool {
+        self.bits() == other.bits()
+    }
+}
+
+impl PartialOrd for Amount {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        self.quantized().partial_cmp(&other.quantized())
+    }
+}
+
+impl Eq for Amount {}
+
+impl core::hash::Hash for Amount {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        self.bits().hash(state);
+    }
+}
+
 // Helper impl to make working with `Amount`s a bit nicer
-impl std::ops::AddAssign for Amount {
+impl core::ops::AddAssign for Amount {
     fn add_assign(&mut self, rhs: Self) {
         self.0 += rhs.0;
     }
 }
 
 // Helper impl to make working with `Amount`s a bit nicer
-impl std::ops::SubAssign for Amount {
+impl core::ops::SubAssign for Amount {
     fn sub_assign(&mut self, rhs: Self) {
         self.0 -= rhs.0;
     }
 }
 
+// Helper impl to make working with `Amount`s a bit nicer
+impl core::ops::Sub for Amount {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        Self(self.0 - rhs.0)
+    }
+}
+
+// Summing saturates at the bounds of f64 instead of overflowing to infinity
+impl core::iter::Sum for Amount {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Amount(0.), Amount::saturating_add)
+    }
+}
+
+impl<'a> core::iter::Sum<&'a Amount> for Amount {
+    fn sum<I: Iterator<Item = &'a Amount>>(iter: I) -> Self {
+        iter.copied().sum()
+    }
+}
+
+impl Amount {
+    /// Create an amount from `val` on the grid of current `precision`, the same as parsed
+    /// from input, eg. `Amount::new(1.23456)` equals `1.23456` read with 4 decimal places
+    ///
+    /// Digits beyond precision are truncated. Constructing `Amount` directly keeps `val`
+    /// as given, which is compared and written rounded instead.
+    pub fn new(val: f64) -> Self {
+        Self::truncated(val)
+    }
+
+    /// Create an amount from `val` truncated to current `precision`, as amounts are read
+    ///
+    /// Values on the grid stay as they are, despite float errors of scaling them,
+    /// see `RoundingMode::TowardZero`
+    pub(crate) fn truncated(val: f64) -> Self {
+        Self(RoundingMode::TowardZero.round(val, precision()))
+    }
+
+    /// Check if reading `val` as an amount changes it, truncating it to current `precision`
+    pub fn loses_precision(val: f64) -> bool {
+        Self::truncated(val).0 != val
+    }
+
+    /// Add `rhs`, clamping the result at the bounds of f64 instead of overflowing to infinity
+    ///
+    /// Meant for aggregations in reports, balances of accounts use the plain operators
+    pub fn saturating_add(self, rhs: Self) -> Self {
+        Self((self.0 + rhs.0).clamp(f64::MIN, f64::MAX))
+    }
+
+    /// Add `rhs`, failing if the result exceeds the bounds of f64
+    pub fn checked_add(self, rhs: Self) -> Result<Self, AmountOverflow> {
+        Some(self.0 + rhs.0)
+            .filter(|sum| sum.is_finite())
+            .map(Self)
+            .ok_or(AmountOverflow)
+    }
+
+    /// Subtract `rhs`, clamping the result at the bounds of f64 instead of overflowing to infinity
+    ///
+    /// Meant for aggregations in reports, balances of accounts use the plain operators
+    pub fn saturating_sub(self, rhs: Self) -> Self {
+        Self((self.0 - rhs.0).clamp(f64::MIN, f64::MAX))
+    }
+
+    /// Bits of the rounded value identifying an amount, the same for both zeros
+    pub(crate) fn bits(self) -> u64 {
+        let val = self.quantized();
+        if val == 0. {
+            0
+        } else {
+            val.to_bits()
+        }
+    }
+
+    /// Value rounded to current `precision`, which amounts are compared by
+    fn quantized(self) -> f64 {
+        RoundingMode::HalfUp.round(self.0, precision())
+    }
+
+    /// Round amount to current `precision` following given rounding `mode`
+    pub fn rounded(self, mode: RoundingMode) -> Self {
+        Self(mode.round(self.0, precision()))
+    }
+}
+
+/// Strategies of rounding amounts when writing them out, or applying them, see `AccountPolicy`
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// Round half away from zero
+    #[default]
+    HalfUp,
+    /// Round half to even, also known as banker's rounding
+    HalfEven,
+    /// Drop digits beyond precision, rounding towards zero
+    TowardZero,
+    /// Round anything beyond precision away from zero
+    AwayFromZero,
+}
+
+/// Tolerance of telling apart values off the precision grid from float errors of scaling
+const GRID_TOLERANCE: f64 = 1e-6;
+
+impl RoundingMode {
+    /// Round `val` to `decimal_places` digits after the decimal point
+    pub fn round(self, val: f64, decimal_places: i32) -> f64 {
+        let factor = math::powi(10., decimal_places);
+        let scaled = val * factor;
+        let rounded = match self {
+            RoundingMode::HalfUp => math::round(scaled),
+            RoundingMode::HalfEven => {
+                // Most of decimal ties aren't representable exactly, eg. 1.00005 * 10^4
+                // gives 10000.500000000002, so ties are detected with a tolerance
+                let floor = math::floor(scaled);
+                if (scaled - floor - 0.5).abs() < GRID_TOLERANCE {
+                    if floor % 2. == 0. {
+                        floor
+                    } else {
+                        floor + 1.
+                    }
+                } else {
+                    math::round(scaled)
+                }
+            }
+            // Values on the grid aren't always scaled exactly either, eg. 1.1 * 10^4 gives
+            // 11000.000000000002, so ones close to it are snapped to it
+            RoundingMode::TowardZero | RoundingMode::AwayFromZero => {
+                let nearest = math::round(scaled);
+                if (scaled - nearest).abs() < GRID_TOLERANCE {
+                    nearest
+                } else if self == RoundingMode::TowardZero {
+                    math::trunc(scaled)
+                } else {
+                    math::trunc(scaled) + if scaled < 0. { -1. } else { 1. }
+                }
+            }
+        };
+        rounded / factor
+    }
+}
+
 /// A module for serialize/deserialize functions used to meet contract of decimal digits precision
 mod serde_amount {
+    use super::{precision, Amount, RoundingMode};
     use serde::{Deserialize, Deserializer, Serializer};
 
-    const DECIMAL_PLACES: i32 = 4;
-
-    /// Serialize function that serializes f64 values rounded to 4 decimal places
+    /// Serialize function that serializes f64 values rounded to current precision
+    ///
+    /// Negative zero, left by balances netting to zero or by rounding tiny negative
+    /// values, is written as a plain zero
     pub fn serialize<S>(val: &f64, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let factor = 10.0_f64.powi(DECIMAL_PLACES);
-        let val = (val * factor).round() / factor;
-        serializer.serialize_f64(val)
+        let val = RoundingMode::HalfUp.round(*val, precision());
+        serializer.serialize_f64(if val == 0. { 0. } else { val })
     }
 
-    /// Deserialize function that deserializes f64 values truncated to 4 decimal places
+    /// Deserialize function that deserializes f64 values truncated to current precision
     pub fn deserialize<'de, D>(deserializer: D) -> Result<f64, D::Error>
     where
         D: Deserializer<'de>,
     {
-        let factor = 10.0_f64.powi(DECIMAL_PLACES);
         let val = f64::deserialize(deserializer)?;
-        let val = (val * factor).trunc() / factor;
-        Ok(val)
+        Ok(Amount::truncated(val).0)
     }
 }
 
@@ -73,6 +384,68 @@ mod serde_amount {
 mod tests {
     use super::*;
 
+    #[test]
+    fn only_deposits_and_withdrawals_should_be_monetary() {
+        let monetary: Vec<_> = [
+            TransactionType::Deposit,
+            TransactionType::Withdrawal,
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+        ]
+        .into_iter()
+        .map(|r#type| {
+            let tx = Transaction {
+                r#type,
+                client: 1,
+                tx: 1,
+                amount: Amount(2.5),
+                idempotency_key: None,
+                timestamp: None,
+            };
+            (tx.is_monetary(), tx.monetary_amount())
+        })
+        .collect();
+
+        assert_eq!(
+            vec![
+                (true, Some(Amount(2.5))),
+                (true, Some(Amount(2.5))),
+                (false, None),
+                (false, None),
+                (false, None),
+            ],
+            monetary
+        );
+    }
+
+    #[test]
+    fn transaction_type_names_should_round_trip() {
+        [
+            TransactionType::Deposit,
+            TransactionType::Withdrawal,
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+        ]
+        .into_iter()
+        .for_each(|r#type| {
+            assert_eq!(Ok(r#type), TransactionType::try_from(r#type.as_str()));
+            assert_eq!(
+                format!("\"{}\"", r#type.as_str()),
+                serde_json::to_string(&r#type).unwrap()
+            );
+        });
+    }
+
+    #[test]
+    fn unknown_transaction_type_should_not_parse() {
+        assert_eq!(
+            Err(UnknownTransactionType("transfer".to_string())),
+            TransactionType::try_from("transfer")
+        );
+    }
+
     #[test]
     fn deserialzed_amount_should_be_truncated() {
         [
@@ -103,4 +476,189 @@ mod tests {
             )
         });
     }
+
+    #[test]
+    fn amounts_should_be_compared_after_rounding() {
+        assert_eq!(Amount(0.3), Amount(0.1 + 0.2));
+        assert!(Amount(0.7 + 0.1) >= Amount(0.8));
+        assert!(Amount(0.8) <= Amount(0.7 + 0.1));
+        assert!(Amount(1.0001) > Amount(1.));
+        assert_ne!(Amount(1.0001), Amount(1.));
+    }
+
+    #[test]
+    fn precision_loss_should_be_detected() {
+        assert!(Amount::loses_precision(1.12349));
+        assert!(Amount::loses_precision(0.00001));
+        assert!(!Amount::loses_precision(1.1234));
+        assert!(!Amount::loses_precision(0.0003));
+        assert!(!Amount::loses_precision(2.0058));
+        assert!(!Amount::loses_precision(0.0029));
+        assert!(!Amount::loses_precision(5.));
+    }
+
+    #[test]
+    fn negative_zero_amount_should_be_serialized_as_zero() {
+        assert_eq!("0.0", serde_json::to_string(&Amount(-0.0)).unwrap());
+        assert_eq!("0.0", serde_json::to_string(&Amount(-0.00001)).unwrap());
+    }
+
+    #[test]
+    fn half_even_rounded_amount_should_round_ties_to_even() {
+        [
+            (1.00005_f64, "1.0"),
+            (1.00015_f64, "1.0002"),
+            (1.00025_f64, "1.0002"),
+            (2.50005_f64, "2.5"),
+            (-1.00005_f64, "-1.0"),
+            (1.12349_f64, "1.1235"),
+        ]
+        .into_iter()
+        .for_each(|(input, expected)| {
+            assert_eq!(
+                expected,
+                serde_json::to_string(&Amount(input).rounded(RoundingMode::HalfEven))
+                    .unwrap()
+                    .as_str()
+            )
+        });
+    }
+
+    #[test]
+    fn directed_rounding_should_follow_direction_off_the_grid_only() {
+        let round = |mode, val| Amount(val).rounded(mode).0;
+
+        assert_eq!(1.2345, round(RoundingMode::TowardZero, 1.23459));
+        assert_eq!(-1.2345, round(RoundingMode::TowardZero, -1.23459));
+        assert_eq!(1.2346, round(RoundingMode::AwayFromZero, 1.23451));
+        assert_eq!(-1.2346, round(RoundingMode::AwayFromZero, -1.23451));
+        assert_eq!(1.1, round(RoundingMode::AwayFromZero, 1.1));
+        assert_eq!(1.0003, round(RoundingMode::TowardZero, 1.0003));
+    }
+
+    #[test]
+    fn half_up_rounded_amount_should_round_ties_away_from_zero() {
+        [
+            (1.00005_f64, "1.0001"),
+            (1.00015_f64, "1.0002"),
+            (1.00025_f64, "1.0003"),
+            (-1.00005_f64, "-1.0001"),
+        ]
+        .into_iter()
+        .for_each(|(input, expected)| {
+            assert_eq!(
+                expected,
+                serde_json::to_string(&Amount(input).rounded(RoundingMode::HalfUp))
+                    .unwrap()
+                    .as_str()
+            )
+        });
+    }
+
+    #[test]
+    fn sum_of_amounts_should_saturate() {
+        let amounts = [Amount(1.5), Amount(2.25), Amount(-0.75)];
+        assert_eq!(Amount(3.), amounts.iter().sum());
+
+        let amounts = [Amount(f64::MAX), Amount(f64::MAX)];
+        assert_eq!(Amount(f64::MAX), amounts.into_iter().sum());
+    }
+
+    #[test]
+    fn saturating_arithmetic_should_clamp_at_bounds() {
+        assert_eq!(Amount(3.5), Amount(1.5).saturating_add(Amount(2.)));
+        assert_eq!(Amount(-0.5), Amount(1.5).saturating_sub(Amount(2.)));
+
+        assert_eq!(
+            Amount(f64::MAX),
+            Amount(f64::MAX).saturating_add(Amount(f64::MAX))
+        );
+        assert_eq!(
+            Amount(f64::MIN),
+            Amount(f64::MIN).saturating_add(Amount(f64::MIN))
+        );
+        assert_eq!(
+            Amount(f64::MAX),
+            Amount(f64::MAX).saturating_sub(Amount(f64::MIN))
+        );
+        assert_eq!(
+            Amount(f64::MIN),
+            Amount(f64::MIN).saturating_sub(Amount(f64::MAX))
+        );
+    }
+
+    #[test]
+    fn identical_transactions_should_be_equal_and_hash_alike() {
+        use std::collections::HashSet;
+
+        let tx = |tx, amount| Transaction {
+            r#type: TransactionType::Deposit,
+            client: 1,
+            tx,
+            amount: Amount(amount),
+            idempotency_key: None,
+            timestamp: None,
+        };
+
+        assert_eq!(tx(1, 1.5), tx(1, 1.5));
+        assert_eq!(tx(1, 0.), tx(1, -0.));
+        assert_ne!(tx(1, 1.5), tx(2, 1.5));
+        assert_ne!(tx(1, 1.5), tx(1, 1.25));
+        let unique: HashSet<_> = [tx(1, 1.5), tx(1, 1.5), tx(1, 0.), tx(1, -0.), tx(2, 1.5)]
+            .into_iter()
+            .collect();
+        assert_eq!(3, unique.len());
+    }
+
+    #[test]
+    fn constructed_amounts_should_match_parsed_ones() {
+        for (val, json) in [
+            (1.23456, "1.23456"),
+            (-1.23456, "-1.23456"),
+            (0.99999, "0.99999"),
+        ] {
+            let parsed: Amount = serde_json::from_str(json).unwrap();
+            assert_eq!(parsed.0.to_bits(), Amount::new(val).0.to_bits());
+            assert_eq!(
+                serde_json::to_string(&parsed).unwrap(),
+                serde_json::to_string(&Amount::new(val)).unwrap()
+            );
+        }
+        assert_eq!(1.2345, Amount::new(1.23456).0);
+        // Values on the grid keep their value, even if scaling them isn't exact
+        assert_eq!(0.0003, Amount::new(0.0003).0);
+        assert_eq!(2.0058, Amount::new(2.0058).0);
+        assert_eq!(0.0003, serde_json::from_str::<Amount>("0.0003").unwrap().0);
+        // Value off the grid is rounded when written, unlike truncated on reading
+        assert_eq!("1.2346", serde_json::to_string(&Amount(1.23456)).unwrap());
+    }
+
+    #[test]
+    fn amounts_should_follow_configured_precision() {
+        set_precision(2);
+        assert_eq!(1.12, serde_json::from_str::<Amount>("1.12987").unwrap().0);
+        assert_eq!("1.13", serde_json::to_string(&Amount(1.125)).unwrap());
+
+        set_precision(8);
+        assert_eq!(
+            1.12345678,
+            serde_json::from_str::<Amount>("1.123456789").unwrap().0
+        );
+        assert_eq!(
+            "1.12345679",
+            serde_json::to_string(&Amount(1.123456786)).unwrap()
+        );
+
+        set_precision(DECIMAL_PLACES);
+        assert_eq!("1.1235", serde_json::to_string(&Amount(1.12349)).unwrap());
+    }
+
+    #[test]
+    fn checked_add_should_fail_on_overflow() {
+        assert_eq!(Ok(Amount(3.5)), Amount(1.5).checked_add(Amount(2.)));
+        assert_eq!(
+            Err(AmountOverflow),
+            Amount(f64::MAX).checked_add(Amount(f64::MAX))
+        );
+    }
 }
@@ -0,0 +1,269 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/// Write `content` to a file in the temporary directory, unique for given test `name`
+fn input_file(name: &str, content: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "transactions-engine-{name}-{}.csv",
+        std::process::id()
+    ));
+    std::fs::write(&path, content).unwrap();
+    path
+}
+
+fn run(args: &[&str], input: &PathBuf) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_transactions-engine"))
+        .args(args)
+        .arg(input)
+        .output()
+        .unwrap()
+}
+
+const FEED_WITH_REJECTED_TX: &str = "type,client,tx,amount\n\
+                                     deposit,1,1,5.0\n\
+                                     withdrawal,1,2,7.0\n\
+                                     deposit,1,3,1.0\n";
+
+#[test]
+fn strict_apply_should_abort_on_rejected_transaction() {
+    let input = input_file("strict", FEED_WITH_REJECTED_TX);
+    let output = run(&["--strict-apply"], &input);
+    std::fs::remove_file(input).unwrap();
+
+    assert_eq!(Some(1), output.status.code());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains(
+            "Transaction `2` of client `1` rejected, aborting: \
+             Unsufficient funds to handle transaction `2`"
+        ),
+        "unexpected stderr: {stderr}"
+    );
+}
+
+#[test]
+fn rejected_transaction_should_not_abort_by_default() {
+    let input = input_file("best-effort", FEED_WITH_REJECTED_TX);
+    let output = run(&[], &input);
+    std::fs::remove_file(input).unwrap();
+
+    assert_eq!(Some(0), output.status.code());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        "id,available,held,total,locked\n1,6.0,0.0,6.0,false\n",
+        stdout
+    );
+}
+
+#[test]
+fn only_active_should_skip_accounts_left_at_zero() {
+    let input = input_file(
+        "only-active",
+        "type,client,tx,amount\n\
+         deposit,1,1,5.0\n\
+         deposit,2,2,3.0\n\
+         withdrawal,2,3,3.0\n\
+         deposit,3,4,2.0\n\
+         dispute,3,4,\n\
+         chargeback,3,4,\n",
+    );
+    let output = run(&["--only-active"], &input);
+    std::fs::remove_file(input).unwrap();
+
+    assert_eq!(Some(0), output.status.code());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut lines: Vec<_> = stdout.lines().collect();
+    lines.sort();
+    assert_eq!(
+        vec![
+            "1,5.0,0.0,5.0,false",
+            "3,0.0,0.0,0.0,true",
+            "id,available,held,total,locked",
+        ],
+        lines
+    );
+}
+
+#[test]
+fn max_errors_should_abort_once_reached() {
+    let input = input_file(
+        "max-errors",
+        "type,client,tx,amount\n\
+         withdrawal,1,1,1.0\n\
+         deposit,1,2,5.0\n\
+         withdrawal,1,3,7.0\n\
+         deposit,1,4,1.0\n",
+    );
+    let aborted = run(&["--max-errors", "2"], &input);
+    let completed = run(&["--max-errors", "3"], &input);
+    std::fs::remove_file(input).unwrap();
+
+    assert_eq!(Some(1), aborted.status.code());
+    assert!(aborted.stdout.is_empty());
+    let stderr = String::from_utf8(aborted.stderr).unwrap();
+    assert!(
+        stderr.contains("Rejected 2 transactions, reaching the limit of --max-errors, aborting"),
+        "unexpected stderr: {stderr}"
+    );
+
+    assert_eq!(Some(0), completed.status.code());
+    assert_eq!(
+        "id,available,held,total,locked\n1,6.0,0.0,6.0,false\n",
+        String::from_utf8(completed.stdout).unwrap()
+    );
+}
+
+#[test]
+fn empty_input_should_give_empty_output_unlike_missing_one() {
+    for (name, content) in [("empty", ""), ("header-only", "type,client,tx,amount\n")] {
+        let input = input_file(name, content);
+        let output = Command::new(env!("CARGO_BIN_EXE_transactions-engine"))
+            .env("RUST_LOG", "info")
+            .arg(&input)
+            .output()
+            .unwrap();
+        std::fs::remove_file(&input).unwrap();
+
+        assert_eq!(Some(0), output.status.code());
+        assert!(output.stdout.is_empty());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(
+            stderr.contains(&format!("Read 0 transactions from {}", input.display())),
+            "unexpected stderr: {stderr}"
+        );
+    }
+
+    let missing = std::env::temp_dir().join("transactions-engine-missing.csv");
+    let output = run(&[], &missing);
+    assert_eq!(Some(1), output.status.code());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Couldn't open file"));
+}
+
+#[test]
+fn output_should_be_written_to_given_path() {
+    let input = input_file("output", FEED_WITH_REJECTED_TX);
+    let output_path = input.with_extension("out.csv");
+    let output = run(&["--output", output_path.to_str().unwrap()], &input);
+    std::fs::remove_file(input).unwrap();
+    let written = std::fs::read_to_string(&output_path).unwrap();
+    std::fs::remove_file(output_path).unwrap();
+
+    assert_eq!(Some(0), output.status.code());
+    assert!(output.stdout.is_empty());
+    assert_eq!(
+        "id,available,held,total,locked\n1,6.0,0.0,6.0,false\n",
+        written
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("E_INSUFFICIENT_FUNDS"),
+        "unexpected stderr: {stderr}"
+    );
+}
+
+#[test]
+fn signature_should_be_written_next_to_output() {
+    let input = input_file("signed", FEED_WITH_REJECTED_TX);
+    let key_path = input.with_extension("key");
+    std::fs::write(&key_path, [7; 32]).unwrap();
+    let output_path = input.with_extension("signed.csv");
+    let output = run(
+        &[
+            "--sign-key",
+            key_path.to_str().unwrap(),
+            "--output",
+            output_path.to_str().unwrap(),
+        ],
+        &input,
+    );
+    let signature_path = input.with_extension("signed.csv.sig");
+    let signature = std::fs::read(&signature_path);
+    let misplaced = input.with_extension("csv.sig").exists();
+    for path in [input, key_path, output_path, signature_path] {
+        let _ = std::fs::remove_file(path);
+    }
+
+    assert_eq!(Some(0), output.status.code());
+    assert_eq!(64, signature.unwrap().len());
+    assert!(!misplaced);
+}
+
+#[test]
+fn byte_order_mark_should_be_skipped() {
+    for (name, format, content) in [
+        (
+            "bom-csv",
+            "csv",
+            "\u{feff}type,client,tx,amount\ndeposit,1,1,5.0\n",
+        ),
+        (
+            "bom-jsonl",
+            "jsonl",
+            "\u{feff}{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":5.0}\n",
+        ),
+    ] {
+        let input = input_file(name, content);
+        let output = run(&["--input-format", format], &input);
+        std::fs::remove_file(input).unwrap();
+
+        assert_eq!(Some(0), output.status.code(), "failed reading {format}");
+        assert_eq!(
+            "id,available,held,total,locked\n1,5.0,0.0,5.0,false\n",
+            String::from_utf8(output.stdout).unwrap()
+        );
+    }
+}
+
+#[test]
+fn records_of_invalid_utf8_should_be_skipped_if_asked_to() {
+    let input = input_file("bad-utf8", "");
+    let mut content = b"type,client,tx,amount\ndeposit,1,1,5.0\n".to_vec();
+    content.extend(b"deposit,1,2,\xff\xfe\n");
+    content.extend(b"deposit,1,3,1.0\n");
+    std::fs::write(&input, content).unwrap();
+    let skipping = run(&["--skip-bad-utf8"], &input);
+    let aborted = run(&[], &input);
+    std::fs::remove_file(input).unwrap();
+
+    assert_eq!(Some(0), skipping.status.code());
+    assert_eq!(
+        "id,available,held,total,locked\n1,6.0,0.0,6.0,false\n",
+        String::from_utf8(skipping.stdout).unwrap()
+    );
+    let stderr = String::from_utf8(skipping.stderr).unwrap();
+    assert!(
+        stderr.contains("Skipped 1 records of"),
+        "unexpected stderr: {stderr}"
+    );
+
+    assert_eq!(Some(1), aborted.status.code());
+    assert!(aborted.stdout.is_empty());
+}
+
+#[test]
+fn sort_input_should_apply_transactions_in_order_of_ids() {
+    let input = input_file(
+        "sort-input",
+        "type,client,tx,amount\n\
+         dispute,1,1,\n\
+         withdrawal,1,3,4.0\n\
+         deposit,1,2,3.0\n\
+         deposit,1,1,5.0\n",
+    );
+    let sorted = run(&["--sort-input"], &input);
+    let unsorted = run(&[], &input);
+    std::fs::remove_file(input).unwrap();
+
+    assert_eq!(Some(0), sorted.status.code());
+    assert_eq!(
+        "id,available,held,total,locked\n1,3.0,5.0,8.0,false\n",
+        String::from_utf8(sorted.stdout).unwrap()
+    );
+    assert_eq!(
+        "id,available,held,total,locked\n1,8.0,0.0,8.0,false\n",
+        String::from_utf8(unsorted.stdout).unwrap()
+    );
+}